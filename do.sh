@@ -18,6 +18,8 @@ deploy() {
   if [ -z ${DEPLOY_ENV} ]; then exit 1; fi
   helm template -f k8s/values.yaml -f k8s/values/${DEPLOY_ENV}.yaml \
     --set docker_registry=${DOCKER_REGISTRY},rev=${REV} k8s/ | kubectl apply -f -
+  # replaced by the StatefulSet
+  kubectl delete deployment ${NAME}-deployment -n dinopark-${DEPLOY_ENV} --ignore-not-found
 }
 
 if [ -z ${1} ]
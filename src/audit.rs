@@ -0,0 +1,234 @@
+use crate::notification::Notification;
+use crate::settings::AuditSettings;
+use crate::sink::Outcomes;
+use crate::updater::Deletion;
+use chrono::DateTime;
+use chrono::Utc;
+use failure::Error;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// One deletion as recorded in the audit trail.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub user_id: String,
+    pub uuid: Option<String>,
+    pub notification: Option<Notification>,
+    pub correlation_id: Option<String>,
+    pub outcomes: Outcomes,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub warning: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+impl AuditEntry {
+    pub fn deletion(
+        user_id: &str,
+        notification: Option<&Notification>,
+        correlation_id: Option<&str>,
+        started_at: DateTime<Utc>,
+        res: &Result<Deletion, Error>,
+    ) -> Self {
+        let (uuid, outcomes, error) = match res {
+            Ok(d) => (Some(d.uuid.clone()), d.outcomes.clone(), None),
+            Err(e) => (None, Outcomes::new(), Some(e.to_string())),
+        };
+        AuditEntry {
+            user_id: user_id.to_owned(),
+            uuid,
+            notification: notification.cloned(),
+            correlation_id: correlation_id.map(ToOwned::to_owned),
+            outcomes,
+            error,
+            warning: None,
+            started_at,
+            finished_at: Utc::now(),
+        }
+    }
+
+    /// A delete that was not executed.
+    pub fn skipped(
+        user_id: &str,
+        notification: Option<&Notification>,
+        correlation_id: Option<&str>,
+        started_at: DateTime<Utc>,
+    ) -> Self {
+        AuditEntry {
+            user_id: user_id.to_owned(),
+            uuid: None,
+            notification: notification.cloned(),
+            correlation_id: correlation_id.map(ToOwned::to_owned),
+            outcomes: Outcomes::new(),
+            error: None,
+            warning: None,
+            started_at,
+            finished_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct AuditQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub user_id: Option<String>,
+    pub uuid: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.from.is_none_or(|from| entry.started_at >= from)
+            && self.to.is_none_or(|to| entry.started_at <= to)
+            && self
+                .user_id
+                .as_ref()
+                .is_none_or(|user_id| &entry.user_id == user_id)
+            && self
+                .uuid
+                .as_ref()
+                .is_none_or(|uuid| entry.uuid.as_ref() == Some(uuid))
+    }
+}
+
+/// Append-only audit trail stored as one JSON entry per line.
+#[derive(Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl AuditLog {
+    pub fn new(settings: &AuditSettings) -> Self {
+        AuditLog {
+            path: PathBuf::from(&settings.path),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn record(&self, entry: &AuditEntry) {
+        if let Err(e) = self.append(entry) {
+            error!("unable to write audit entry for {}: {}", entry.user_id, e);
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> Result<(), Error> {
+        let line = serde_json::to_string(entry)?;
+        let _lock = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Returns the most recent entries matching `query`, oldest first. Lines are appended whole,
+    /// so reading does not wait for writers. Lines that cannot be parsed are skipped.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, Error> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = vec![];
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let entry: AuditEntry = match serde_json::from_str(&line?) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("skipping garbled audit entry on line {}: {}", i + 1, e);
+                    continue;
+                }
+            };
+            if query.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+        if let Some(limit) = query.limit {
+            let skip = entries.len().saturating_sub(limit);
+            entries.drain(..skip);
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::notification::Operation;
+
+    #[test]
+    fn test_query_filters_entries() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let log = AuditLog::new(&AuditSettings {
+            path: path.to_string_lossy().into_owned(),
+            ..Default::default()
+        });
+        let n = Notification {
+            operation: Operation::Delete,
+            id: String::from("ad|Mozilla-LDAP|hknall"),
+            time: 1551963674.0,
+        };
+        let started_at = Utc::now();
+        log.record(&AuditEntry::deletion(
+            &n.id,
+            Some(&n),
+            None,
+            started_at,
+            &Err(failure::format_err!("cannot resolve uuid")),
+        ));
+        log.record(&AuditEntry::deletion(
+            "other",
+            None,
+            None,
+            started_at,
+            &Ok(Deletion {
+                uuid: String::from("some-uuid"),
+                outcomes: Outcomes::new(),
+            }),
+        ));
+
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"user_id\": \"trunc\n")
+            .unwrap();
+
+        let all = log.query(&AuditQuery::default()).unwrap();
+        assert_eq!(all.len(), 2);
+        let by_user = log
+            .query(&AuditQuery {
+                user_id: Some(n.id.clone()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(by_user.len(), 1);
+        assert_eq!(by_user[0].error.as_deref(), Some("cannot resolve uuid"));
+        let by_uuid = log
+            .query(&AuditQuery {
+                uuid: Some(String::from("some-uuid")),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(by_uuid[0].user_id, "other");
+        let later = log
+            .query(&AuditQuery {
+                from: Some(Utc::now() + chrono::Duration::hours(1)),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(later.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
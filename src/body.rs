@@ -0,0 +1,312 @@
+//! Request bodies sent to the sinks, compressed for sinks that want it.
+use crate::payload;
+use crate::settings::BulkEncoding;
+use crate::settings::SinkSettings;
+use bytes::Bytes;
+use cis_profile::schema::Profile;
+use failure::Error;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::header::CONTENT_ENCODING;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::RequestBuilder;
+use serde_json::Value;
+use std::io;
+use std::io::Write;
+use uuid::Uuid;
+
+/// Cloning is cheap, the bytes are shared.
+#[derive(Clone)]
+pub struct Body {
+    content_type: String,
+    gzip: bool,
+    bytes: Bytes,
+}
+
+impl Body {
+    pub fn json(value: &Value, settings: &SinkSettings) -> Result<Self, Error> {
+        let mut out = Output::new(settings);
+        serde_json::to_writer(&mut out, value)?;
+        Ok(Body {
+            content_type: String::from("application/json"),
+            gzip: settings.gzip,
+            bytes: out.finish()?,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+/// Compresses while writing if the sink wants gzip.
+enum Output {
+    Plain(Vec<u8>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Output {
+    fn new(settings: &SinkSettings) -> Self {
+        if settings.gzip {
+            Output::Gzip(GzEncoder::new(Vec::new(), Compression::default()))
+        } else {
+            Output::Plain(Vec::new())
+        }
+    }
+
+    /// Bytes written so far, after compression.
+    fn len(&self) -> usize {
+        match self {
+            Output::Plain(bytes) => bytes.len(),
+            Output::Gzip(encoder) => encoder.get_ref().len(),
+        }
+    }
+
+    fn finish(self) -> Result<Bytes, Error> {
+        Ok(match self {
+            Output::Plain(bytes) => bytes.into(),
+            Output::Gzip(encoder) => encoder.finish()?.into(),
+        })
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(bytes) => bytes.write(buf),
+            Output::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(bytes) => bytes.flush(),
+            Output::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Writes a bulk body one profile at a time in the sink's encoding.
+struct BulkWriter {
+    encoding: BulkEncoding,
+    boundary: String,
+    gzip: bool,
+    out: Output,
+    empty: bool,
+}
+
+impl BulkWriter {
+    fn new(settings: &SinkSettings) -> Result<Self, Error> {
+        let boundary = Uuid::new_v4().simple().to_string();
+        let mut out = Output::new(settings);
+        match settings.bulk_encoding {
+            BulkEncoding::Multipart => write!(
+                out,
+                "--{}\r\nContent-Disposition: form-data; name=\"data\"; filename=\"data\"\r\n\
+                 Content-Type: application/json\r\n\r\n[",
+                boundary
+            )?,
+            BulkEncoding::Array => out.write_all(b"[")?,
+            BulkEncoding::Ndjson => {}
+        }
+        Ok(BulkWriter {
+            encoding: settings.bulk_encoding,
+            boundary,
+            gzip: settings.gzip,
+            out,
+            empty: true,
+        })
+    }
+
+    fn push(&mut self, value: &Value) -> Result<(), Error> {
+        if !self.empty && self.encoding != BulkEncoding::Ndjson {
+            self.out.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut self.out, value)?;
+        if self.encoding == BulkEncoding::Ndjson {
+            self.out.write_all(b"\n")?;
+        }
+        self.empty = false;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Body, Error> {
+        let content_type = match self.encoding {
+            BulkEncoding::Multipart => {
+                write!(self.out, "]\r\n--{}--\r\n", self.boundary)?;
+                format!("multipart/form-data; boundary={}", self.boundary)
+            }
+            BulkEncoding::Array => {
+                self.out.write_all(b"]")?;
+                String::from("application/json")
+            }
+            BulkEncoding::Ndjson => String::from("application/x-ndjson"),
+        };
+        Ok(Body {
+            content_type,
+            gzip: self.gzip,
+            bytes: self.out.finish()?,
+        })
+    }
+}
+
+/// Bulk bodies for the first `consumed` profiles of a page, `count` of which were written.
+pub struct BulkBodies {
+    pub consumed: usize,
+    pub count: usize,
+    pub bodies: Vec<Body>,
+}
+
+/// Serializes profiles from the start of a page into one bulk body per sink, stopping once a
+/// body reaches `max_bytes` (`0` for no limit) so the rest of the page goes into the next
+/// bodies. Profiles are written as they are prepared and sinks with the same settings share a
+/// single body. Profiles that cannot be serialized are skipped.
+pub fn bulk(
+    profiles: &[Profile],
+    sinks: &[&SinkSettings],
+    max_bytes: usize,
+) -> Result<BulkBodies, Error> {
+    let mut distinct: Vec<&SinkSettings> = vec![];
+    let index = sinks
+        .iter()
+        .map(
+            |&settings| match distinct.iter().position(|&d| d == settings) {
+                Some(i) => i,
+                None => {
+                    distinct.push(settings);
+                    distinct.len() - 1
+                }
+            },
+        )
+        .collect::<Vec<_>>();
+    let mut writers = distinct
+        .iter()
+        .map(|settings| BulkWriter::new(settings))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut consumed = 0;
+    let mut count = 0;
+    for profile in profiles {
+        consumed += 1;
+        let value = match payload::serialize(profile) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("skipping profile in batch: {}", e);
+                continue;
+            }
+        };
+        for (writer, settings) in writers.iter_mut().zip(&distinct) {
+            writer.push(&payload::prepare(value.clone(), settings))?;
+        }
+        count += 1;
+        if max_bytes > 0 && writers.iter().any(|w| w.out.len() >= max_bytes) {
+            break;
+        }
+    }
+    let bodies = writers
+        .into_iter()
+        .map(BulkWriter::finish)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(BulkBodies {
+        consumed,
+        count,
+        bodies: index.iter().map(|&i| bodies[i].clone()).collect(),
+    })
+}
+
+pub trait WithBody {
+    fn sink_body(self, body: Body) -> Self;
+}
+
+impl WithBody for RequestBuilder {
+    /// The shared bytes are sent as they are, without copying them per sink.
+    fn sink_body(self, body: Body) -> Self {
+        let req = self.header(CONTENT_TYPE, body.content_type);
+        let req = if body.gzip {
+            req.header(CONTENT_ENCODING, "gzip")
+        } else {
+            req
+        };
+        req.body(body.bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use serde_json::json;
+    use std::io::Read;
+
+    #[test]
+    fn test_gzip_multipart() {
+        let settings = SinkSettings {
+            gzip: true,
+            ..Default::default()
+        };
+        let mut writer = BulkWriter::new(&settings).unwrap();
+        writer.push(&json!({ "user_id": "hknall" })).unwrap();
+        writer.push(&json!({ "user_id": "fiona" })).unwrap();
+        let body = writer.finish().unwrap();
+        let boundary = body.content_type.rsplit('=').next().unwrap().to_owned();
+        let mut decoded = String::new();
+        GzDecoder::new(&body.bytes[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert!(decoded.starts_with(&format!("--{}\r\n", boundary)));
+        assert!(decoded.contains("\r\n\r\n[{\"user_id\":\"hknall\"},{\"user_id\":\"fiona\"}]\r\n"));
+        assert!(decoded.ends_with(&format!("\r\n--{}--\r\n", boundary)));
+    }
+
+    #[test]
+    fn test_bulk_cuts_bodies_at_max_bytes() {
+        let profiles = vec![Profile::default(), Profile::default(), Profile::default()];
+        let ndjson = SinkSettings {
+            bulk_encoding: BulkEncoding::Ndjson,
+            ..Default::default()
+        };
+        let batch = bulk(&profiles, &[&ndjson, &ndjson], 1).unwrap();
+        assert_eq!((batch.consumed, batch.count), (1, 1));
+        assert_eq!(batch.bodies.len(), 2);
+        assert_eq!(
+            batch.bodies[0].bytes.as_ptr(),
+            batch.bodies[1].bytes.as_ptr()
+        );
+        let batch = bulk(&profiles, &[&ndjson], 0).unwrap();
+        assert_eq!((batch.consumed, batch.count), (3, 3));
+        assert_eq!(
+            batch.bodies[0]
+                .bytes
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_ndjson_and_array() {
+        for (encoding, content_type, expected) in [
+            (
+                BulkEncoding::Ndjson,
+                "application/x-ndjson",
+                "{\"user_id\":\"hknall\"}\n{\"user_id\":\"fiona\"}\n",
+            ),
+            (
+                BulkEncoding::Array,
+                "application/json",
+                "[{\"user_id\":\"hknall\"},{\"user_id\":\"fiona\"}]",
+            ),
+        ] {
+            let settings = SinkSettings {
+                bulk_encoding: encoding,
+                ..Default::default()
+            };
+            let mut writer = BulkWriter::new(&settings).unwrap();
+            writer.push(&json!({ "user_id": "hknall" })).unwrap();
+            writer.push(&json!({ "user_id": "fiona" })).unwrap();
+            let body = writer.finish().unwrap();
+            assert_eq!(body.content_type, content_type);
+            assert_eq!(&body.bytes[..], expected.as_bytes());
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Fetches single profiles from the CIS person API. `cis_client` cannot send extra headers,
+//! this sends the correlation id and trace context of the current event along.
+use crate::context::WithCorrelationId;
+use crate::error::ProfileNotFound;
+use crate::telemetry::SendTraced;
+use cis_client::getby::GetBy;
+use cis_client::settings::CisSettings;
+use cis_client::settings::ClientConfig;
+use cis_profile::schema::Profile;
+use failure::format_err;
+use failure::Error;
+use reqwest::Client;
+use reqwest::StatusCode;
+use serde_json::json;
+use serde_json::Value;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use url::Url;
+
+/// Tokens are renewed this long before they expire.
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+pub struct Token {
+    pub access_token: String,
+    pub expires_in: Duration,
+}
+
+/// Requests a token from the CIS token endpoint with the configured client credentials.
+pub async fn fetch_token(config: &ClientConfig) -> Result<Token, Error> {
+    let res = Client::new()
+        .post(&config.token_endpoint)
+        .json(&json!({
+            "client_id": config.client_id,
+            "client_secret": config.client_secret,
+            "audience": config.audience,
+            "scopes": config.scopes,
+            "grant_type": "client_credentials",
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Value>()
+        .await?;
+    match res.get("access_token") {
+        Some(Value::String(access_token)) => Ok(Token {
+            access_token: access_token.clone(),
+            expires_in: Duration::from_secs(res["expires_in"].as_u64().unwrap_or_default()),
+        }),
+        _ => Err(format_err!("no access token in response")),
+    }
+}
+
+#[derive(Clone)]
+pub struct PersonApi {
+    client_config: ClientConfig,
+    user_endpoint: String,
+    client: Client,
+    token: Arc<Mutex<Option<(String, Instant)>>>,
+}
+
+impl PersonApi {
+    pub fn new(settings: CisSettings) -> Self {
+        PersonApi::with_endpoint(settings.client_config, settings.person_api_user_endpoint)
+    }
+
+    pub fn with_endpoint(client_config: ClientConfig, user_endpoint: String) -> Self {
+        PersonApi {
+            client_config,
+            user_endpoint,
+            client: Client::new(),
+            token: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn bearer_token(&self) -> Result<String, Error> {
+        if let Some((token, valid_until)) = &*self.token.lock().unwrap() {
+            if Instant::now() < *valid_until {
+                return Ok(token.clone());
+            }
+        }
+        let token = fetch_token(&self.client_config).await?;
+        let valid_until = Instant::now() + token.expires_in.saturating_sub(TOKEN_MARGIN);
+        *self.token.lock().unwrap() = Some((token.access_token.clone(), valid_until));
+        Ok(token.access_token)
+    }
+
+    async fn get(&self, id: &str, by: &GetBy, active: bool) -> Result<Profile, Error> {
+        let mut url = Url::parse(&self.user_endpoint)?.join(by.as_str())?;
+        url.path_segments_mut()
+            .map_err(|_| format_err!("invalid person api endpoint"))?
+            .pop_if_empty()
+            .push(id);
+        if !active {
+            url.query_pairs_mut().append_pair("active", "false");
+        }
+        let token = self.bearer_token().await?;
+        let res = self
+            .client
+            .get(url)
+            .bearer_auth(token)
+            .with_correlation_id()
+            .send_traced("cis_get_user")
+            .await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Err(ProfileNotFound(id.to_owned()).into());
+        }
+        Ok(res.error_for_status()?.json().await?)
+    }
+
+    pub async fn get_user_by(&self, id: &str, by: &GetBy) -> Result<Profile, Error> {
+        self.get(id, by, true).await
+    }
+
+    pub async fn get_inactive_user_by(&self, id: &str, by: &GetBy) -> Result<Profile, Error> {
+        self.get(id, by, false).await
+    }
+}
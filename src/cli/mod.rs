@@ -0,0 +1,32 @@
+pub mod oneshot;
+pub mod preview;
+pub mod replay;
+
+use clap::Parser;
+use clap::Subcommand;
+
+#[derive(Parser)]
+#[command(
+    name = "lookout",
+    about = "DinoPark Lookout, updating Dinos since 2019"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the http server and the updater (default).
+    Serve,
+    /// Replay notifications from a JSONL file through the update and delete path.
+    Replay(replay::ReplayArgs),
+    /// Update a single profile in all DinoPark services.
+    Update(oneshot::UserArgs),
+    /// Delete a single profile from all DinoPark services, without checking CIS first.
+    Delete(oneshot::UserArgs),
+    /// Update all profiles in all DinoPark services.
+    Bulk(oneshot::BulkArgs),
+    /// Print the payload each sink receives for a sample profile.
+    Preview(preview::PreviewArgs),
+}
@@ -0,0 +1,87 @@
+//! Runs a single update, delete or bulk update without starting the server. Failures are
+//! returned so the process exits with a non-zero status.
+use crate::audit::AuditEntry;
+use crate::audit::AuditLog;
+use crate::cis::PersonApi;
+use crate::lookup::Lookup;
+use crate::settings::Settings;
+use crate::updater;
+use chrono::Utc;
+use cis_client::CisClient;
+use clap::Args;
+use failure::format_err;
+use failure::Error;
+use serde_json::json;
+use tokio::runtime::Runtime;
+
+#[derive(Args)]
+pub struct UserArgs {
+    /// Identifies the profile, a CIS user_id unless `--by` says otherwise.
+    pub id: String,
+    #[arg(long, value_enum, default_value_t = Lookup::UserId)]
+    pub by: Lookup,
+}
+
+#[derive(Args)]
+pub struct BulkArgs {
+    /// CIS filter restricting which profiles are fetched.
+    #[arg(long)]
+    pub filter: Option<String>,
+}
+
+async fn cis_client(s: &Settings) -> Result<CisClient, Error> {
+    CisClient::from_settings(&s.cis)
+        .await
+        .map_err(|e| format_err!("unable to create cis_client: {}", e))
+}
+
+pub fn update(s: Settings, args: UserArgs) -> Result<(), Error> {
+    let person_api = PersonApi::new(s.cis.clone());
+    let update = Runtime::new()?.block_on(updater::resync(
+        &person_api,
+        &s.dino_park,
+        &args.id,
+        args.by,
+    ))?;
+    println!("{}", json!(update));
+    if !update.is_ok() {
+        return Err(format_err!("failed to update {}", update.user_id));
+    }
+    Ok(())
+}
+
+pub fn delete(s: Settings, args: UserArgs) -> Result<(), Error> {
+    let started_at = Utc::now();
+    let person_api = PersonApi::new(s.cis.clone());
+    let (user_id, res) = Runtime::new()?.block_on(async {
+        match updater::delete_target(&person_api, &s.dino_park, &args.id, args.by).await {
+            Ok(target) => {
+                let user_id = target.user_id.unwrap_or_else(|| String::from("unknown"));
+                let deletion = updater::delete_uuid(&s.dino_park, &user_id, target.uuid).await;
+                (user_id, Ok(deletion))
+            }
+            Err(e) => (args.id.clone(), Err(e)),
+        }
+    });
+    AuditLog::new(&s.audit).record(&AuditEntry::deletion(
+        &user_id, None, None, started_at, &res,
+    ));
+    let deletion = res?;
+    println!("{}", json!(deletion));
+    deletion.into_result().map(|_| ())
+}
+
+/// The runtime the CIS client was created on stays alive for the whole update.
+pub fn bulk(s: Settings, args: BulkArgs) -> Result<(), Error> {
+    let rt = Runtime::new()?;
+    let cis_client = rt.block_on(cis_client(&s))?;
+    let update = updater::update_batch(
+        &rt,
+        &cis_client,
+        &s.dino_park,
+        &s.updater.bulk,
+        args.filter.as_deref(),
+    )?;
+    println!("{}", json!(update));
+    update.into_result().map(|_| ())
+}
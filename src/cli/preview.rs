@@ -0,0 +1,36 @@
+use crate::payload;
+use crate::settings::Settings;
+use cis_profile::schema::Profile;
+use clap::Args;
+use failure::Error;
+use serde_json::json;
+use std::fs::File;
+use std::io::stdin;
+use std::io::Read;
+
+#[derive(Args)]
+pub struct PreviewArgs {
+    /// File with a sample profile as returned by CIS, `-` reads from stdin.
+    #[arg(default_value = "-")]
+    pub file: String,
+}
+
+/// Prints the payload every sink would receive for the sample profile.
+pub fn run(s: Settings, args: PreviewArgs) -> Result<(), Error> {
+    let mut input: Box<dyn Read> = match args.file.as_str() {
+        "-" => Box::new(stdin()),
+        path => Box::new(File::open(path)?),
+    };
+    let mut raw = String::new();
+    input.read_to_string(&mut raw)?;
+    let profile: Profile = serde_json::from_str(&raw)?;
+    let value = payload::serialize(&profile)?;
+    let sinks = &s.dino_park.sinks;
+    let preview = json!({
+        "orgchart": payload::prepare(value.clone(), &sinks.orgchart),
+        "search": payload::prepare(value.clone(), &sinks.search),
+        "groups": payload::prepare(value, &sinks.groups),
+    });
+    println!("{}", serde_json::to_string_pretty(&preview)?);
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use crate::audit::AuditLog;
+use crate::cis::PersonApi;
+use crate::context::EventContext;
+use crate::events::cloudevents::Incoming;
+use crate::pending::PendingDeletes;
+use crate::settings::Settings;
+use crate::settings::UpdaterSettings;
+use crate::updater::InternalUpdater;
+use cis_client::CisClient;
+use clap::Args;
+use failure::format_err;
+use failure::Error;
+use serde_json::json;
+use std::fs::File;
+use std::io::stdin;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+#[derive(Args)]
+pub struct ReplayArgs {
+    /// File with one notification per line, `-` reads from stdin.
+    #[arg(default_value = "-")]
+    pub file: String,
+    /// Maximum notifications per second, unlimited if not set.
+    #[arg(long)]
+    pub rate: Option<f64>,
+    /// Number of lines to skip, e.g. to resume an interrupted replay.
+    #[arg(long, default_value_t = 0)]
+    pub offset: usize,
+    /// Only parse and print the notifications.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Serialize, Default)]
+struct Summary {
+    lines: usize,
+    malformed: usize,
+    succeeded: usize,
+    failed: usize,
+    dry_run: usize,
+}
+
+/// Processes every notification in order. Deletes run right away regardless of
+/// `updater.delete_grace_period` since nothing outlives the replay.
+pub fn run(s: Settings, args: ReplayArgs) -> Result<(), Error> {
+    let reader: Box<dyn BufRead> = match args.file.as_str() {
+        "-" => Box::new(BufReader::new(stdin())),
+        path => Box::new(BufReader::new(File::open(path)?)),
+    };
+    let rt = Runtime::new()?;
+    let summary = rt.block_on(async {
+        let updater = if args.dry_run {
+            None
+        } else {
+            let cis_client = CisClient::from_settings(&s.cis)
+                .await
+                .map_err(|e| format_err!("unable to create cis_client: {}", e))?;
+            Some(InternalUpdater::new(
+                cis_client,
+                PersonApi::new(s.cis.clone()),
+                s.dino_park.clone(),
+                UpdaterSettings {
+                    delete_grace_period: 0,
+                    ..s.updater.clone()
+                },
+                AuditLog::new(&s.audit),
+                PendingDeletes::default(),
+            ))
+        };
+        let mut interval = args
+            .rate
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| tokio::time::interval(Duration::from_secs_f64(1.0 / rate)));
+        let mut summary = Summary::default();
+        for (i, line) in reader.lines().enumerate().skip(args.offset) {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            summary.lines += 1;
+            let n = match serde_json::from_str(&line)
+                .map_err(Error::from)
+                .and_then(Incoming::from_value)
+            {
+                Ok(incoming) => incoming.into_notification(),
+                Err(e) => {
+                    warn!("malformed notification on line {}: {}", i + 1, e);
+                    summary.malformed += 1;
+                    continue;
+                }
+            };
+            let updater = match &updater {
+                Some(updater) => updater,
+                None => {
+                    println!(
+                        "{}",
+                        json!({ "line": i + 1, "operation": n.operation, "id": n.id })
+                    );
+                    summary.dry_run += 1;
+                    continue;
+                }
+            };
+            if let Some(interval) = interval.as_mut() {
+                interval.tick().await;
+            }
+            let ctx = EventContext::default();
+            match ctx.scope(updater.process(&n, &ctx)).await {
+                Ok(_) => summary.succeeded += 1,
+                Err(e) => {
+                    warn!("line {}: unable to process {}: {}", i + 1, n.id, e);
+                    summary.failed += 1;
+                }
+            }
+        }
+        Ok::<_, Error>(summary)
+    })?;
+    println!("{}", json!(summary));
+    if summary.failed > 0 {
+        return Err(format_err!("{} notifications failed", summary.failed));
+    }
+    Ok(())
+}
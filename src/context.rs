@@ -0,0 +1,75 @@
+use crate::telemetry;
+use actix_web::HttpRequest;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use reqwest::RequestBuilder;
+use std::future::Future;
+use uuid::Uuid;
+
+pub const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
+tokio::task_local! {
+    static CORRELATION_ID: String;
+}
+
+/// Context assigned when an event enters lookout and carried through the queue to every
+/// request made on its behalf.
+#[derive(Clone, Debug)]
+pub struct EventContext {
+    pub correlation_id: String,
+    pub trace: Context,
+}
+
+impl Default for EventContext {
+    fn default() -> Self {
+        EventContext {
+            correlation_id: Uuid::new_v4().to_string(),
+            trace: Context::current(),
+        }
+    }
+}
+
+impl EventContext {
+    /// Reuses the caller's correlation id and trace context if they were sent along.
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let correlation_id = match req
+            .headers()
+            .get(CORRELATION_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(id) if !id.is_empty() => id.to_owned(),
+            _ => Uuid::new_v4().to_string(),
+        };
+        EventContext {
+            correlation_id,
+            trace: telemetry::extract(req.headers()),
+        }
+    }
+
+    /// Runs `f` with this context attached to every log line, span and outgoing request.
+    pub async fn scope<F: Future>(&self, f: F) -> F::Output {
+        CORRELATION_ID
+            .scope(
+                self.correlation_id.clone(),
+                f.with_context(self.trace.clone()),
+            )
+            .await
+    }
+}
+
+pub fn correlation_id() -> Option<String> {
+    CORRELATION_ID.try_with(|id| id.clone()).ok()
+}
+
+pub trait WithCorrelationId {
+    fn with_correlation_id(self) -> Self;
+}
+
+impl WithCorrelationId for RequestBuilder {
+    fn with_correlation_id(self) -> Self {
+        match correlation_id() {
+            Some(id) => self.header(CORRELATION_ID_HEADER, id),
+            None => self,
+        }
+    }
+}
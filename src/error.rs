@@ -24,3 +24,24 @@ pub enum UpdateError {
     #[fail(display = "error updating")]
     Other,
 }
+
+#[derive(Debug, Fail)]
+#[fail(display = "unverifiable attributes for {}: {}", user_id, attributes)]
+pub struct UnverifiedProfile {
+    pub user_id: String,
+    pub attributes: String,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "no profile in CIS for {}", _0)]
+pub struct ProfileNotFound(pub String);
+
+#[derive(Debug, Fail)]
+#[fail(
+    display = "unable to confirm delete of {} with CIS: {}",
+    user_id, cause
+)]
+pub struct UnconfirmedDelete {
+    pub user_id: String,
+    pub cause: String,
+}
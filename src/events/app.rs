@@ -1,25 +1,208 @@
+use crate::context::EventContext;
+use crate::events::cloudevents::Dedup;
+use crate::events::cloudevents::Incoming;
 use crate::notification::Notification;
+use crate::telemetry;
 use crate::updater::UpdaterClient;
 use actix_web::dev::HttpServiceFactory;
+use actix_web::error;
 use actix_web::web;
 use actix_web::web::Data;
 use actix_web::web::Json;
+use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::Result;
+use opentelemetry::trace::Status;
+use opentelemetry::trace::TraceContextExt;
 use serde_json::json;
+use serde_json::Value;
+use std::future::Future;
 
+/// Batches of notifications may be large during mass changes.
+const MAX_PAYLOAD: usize = 1024 * 1024;
+
+/// Runs a handler in an `update_event` span, which ends once the handler returned and everything
+/// it received is enqueued.
+pub async fn in_event_span<F, Fut>(mut ctx: EventContext, handle: F) -> Result<HttpResponse>
+where
+    F: FnOnce(EventContext) -> Fut,
+    Fut: Future<Output = Result<HttpResponse>>,
+{
+    ctx.trace = telemetry::start("update_event", &ctx.trace);
+    let scope = ctx.clone();
+    let res = scope.scope(handle(ctx)).await;
+    if let Err(ref e) = res {
+        scope.trace.span().set_status(Status::error(e.to_string()));
+    }
+    scope.trace.span().end();
+    res
+}
+
+/// Called within `in_event_span`, queued notifications carry the span along.
+pub async fn enqueue<U: UpdaterClient>(updater: &U, n: Notification, ctx: EventContext) {
+    ctx.scope(async { info!("received {:?} for {}", n.operation, n.id) })
+        .await;
+    updater.update(n, ctx);
+}
+
+/// Enqueues the notification unless it is a redelivered CloudEvent.
+async fn accept<U: UpdaterClient>(
+    updater: &U,
+    dedup: &Dedup,
+    incoming: Incoming,
+    ctx: EventContext,
+) -> &'static str {
+    if !dedup.first_seen(&incoming) {
+        info!("dropping duplicate event ({})", ctx.correlation_id);
+        return "duplicate";
+    }
+    enqueue(updater, incoming.into_notification(), ctx).await;
+    "accepted"
+}
+
+/// Accepts a single notification or an array of them, each either plain or as a structured
+/// CloudEvent, or a single binary mode CloudEvent. Malformed entries in an array are rejected
+/// individually while the rest are enqueued.
 async fn update_event<U: UpdaterClient + Clone + 'static>(
+    req: HttpRequest,
     updater: Data<U>,
-    n: Json<Notification>,
+    dedup: Data<Dedup>,
+    payload: Json<Value>,
 ) -> Result<HttpResponse> {
-    updater.update(n.0);
-    Ok(HttpResponse::Ok().json(json!({})))
+    let ctx = EventContext::from_request(&req);
+    in_event_span(ctx, |ctx| receive(req, updater, dedup, payload, ctx)).await
+}
+
+async fn receive<U: UpdaterClient>(
+    req: HttpRequest,
+    updater: Data<U>,
+    dedup: Data<Dedup>,
+    payload: Json<Value>,
+    ctx: EventContext,
+) -> Result<HttpResponse> {
+    let correlation_id = ctx.correlation_id.clone();
+    let single = match Incoming::from_binary(&req, &payload) {
+        Some(incoming) => incoming,
+        None => match payload.0 {
+            Value::Array(items) => {
+                let mut accepted = 0;
+                let mut statuses = Vec::with_capacity(items.len());
+                for (i, item) in items.into_iter().enumerate() {
+                    let status = match Incoming::from_value(item) {
+                        Ok(incoming) => {
+                            let item_ctx = EventContext {
+                                correlation_id: format!("{}-{}", correlation_id, i),
+                                trace: ctx.trace.clone(),
+                            };
+                            let item_correlation_id = item_ctx.correlation_id.clone();
+                            let status = accept(&**updater, &dedup, incoming, item_ctx).await;
+                            if status == "accepted" {
+                                accepted += 1;
+                            }
+                            json!({ "status": status, "correlation_id": item_correlation_id })
+                        }
+                        Err(e) => json!({ "status": "rejected", "error": e.to_string() }),
+                    };
+                    statuses.push(status);
+                }
+                info!(
+                    "received batch of {} notifications, {} accepted ({})",
+                    statuses.len(),
+                    accepted,
+                    correlation_id
+                );
+                return Ok(HttpResponse::Ok().json(json!({
+                    "correlation_id": correlation_id,
+                    "accepted": accepted,
+                    "items": statuses,
+                })));
+            }
+            single => Incoming::from_value(single),
+        },
+    };
+    let incoming = single.map_err(error::ErrorBadRequest)?;
+    let status = accept(&**updater, &dedup, incoming, ctx).await;
+    Ok(HttpResponse::Ok().json(json!({ "correlation_id": correlation_id, "status": status })))
 }
 
 pub fn update_app<U: UpdaterClient + Clone + Send + 'static>(
     updater: U,
+    dedup: Data<Dedup>,
 ) -> impl HttpServiceFactory {
     web::scope("/update")
         .app_data(Data::new(updater))
+        .app_data(dedup)
+        .app_data(web::JsonConfig::default().limit(MAX_PAYLOAD))
         .service(web::resource("").route(web::post().to(update_event::<U>)))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bulk::Bulk;
+    use actix_web::test;
+    use actix_web::App;
+    use futures::channel::oneshot;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    /// Records the notifications that reach the updater.
+    #[derive(Clone, Default)]
+    struct StandInUpdater {
+        received: Arc<Mutex<Vec<(Notification, String)>>>,
+    }
+
+    impl UpdaterClient for StandInUpdater {
+        fn update(&self, n: Notification, ctx: EventContext) {
+            self.received.lock().unwrap().push((n, ctx.correlation_id));
+        }
+        fn update_acked(&self, _: Notification, _: EventContext) -> oneshot::Receiver<bool> {
+            unimplemented!()
+        }
+        fn update_all(&self, _: Bulk) {}
+        fn stop(&self) {}
+    }
+
+    #[actix_rt::test]
+    async fn test_malformed_array_items_are_rejected_individually() {
+        let updater = StandInUpdater::default();
+        let app = test::init_service(
+            App::new().service(update_app(updater.clone(), Data::new(Dedup::default()))),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/update")
+            .insert_header(("x-correlation-id", "batch"))
+            .set_json(json!([
+                { "operation": "update", "id": "ad|Mozilla-LDAP|hknall", "time": 1551963674.0 },
+                { "operation": "update", "time": 1551963674.0 },
+                {
+                    "specversion": "1.0",
+                    "id": "42",
+                    "source": "cis",
+                    "type": "profile.changed",
+                    "data": { "operation": "delete", "id": "fiona", "time": 1551963674.0 },
+                },
+            ]))
+            .to_request();
+        let res: Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(res["accepted"], 2);
+        let items = res["items"].as_array().unwrap();
+        assert_eq!(items[0]["status"], "accepted");
+        assert_eq!(items[0]["correlation_id"], "batch-0");
+        assert_eq!(items[1]["status"], "rejected");
+        assert!(items[1]["error"].as_str().unwrap().contains("id"));
+        assert_eq!(items[2]["status"], "accepted");
+        assert_eq!(items[2]["correlation_id"], "batch-2");
+        let received = updater.received.lock().unwrap();
+        let received: Vec<_> = received
+            .iter()
+            .map(|(n, correlation_id)| (n.id.as_str(), correlation_id.as_str()))
+            .collect();
+        assert_eq!(
+            received,
+            vec![("ad|Mozilla-LDAP|hknall", "batch-0"), ("fiona", "batch-2")]
+        );
+    }
+}
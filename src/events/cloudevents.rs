@@ -0,0 +1,170 @@
+//! CloudEvents 1.0 carrying a `Notification` as data, in structured or binary content mode.
+use crate::notification::Notification;
+use actix_web::HttpRequest;
+use failure::format_err;
+use failure::Error;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+const SPECVERSION: &str = "1.0";
+/// Number of recent event ids remembered for deduplication.
+const DEDUP_CAPACITY: usize = 10_000;
+
+#[derive(Deserialize)]
+struct StructuredEvent {
+    specversion: String,
+    id: String,
+    source: String,
+    #[serde(rename = "type")]
+    _type: String,
+    data: Notification,
+}
+
+#[derive(Debug)]
+pub struct CloudEvent {
+    pub id: String,
+    pub source: String,
+    pub notification: Notification,
+}
+
+impl CloudEvent {
+    /// Events are unique by source and id.
+    fn key(&self) -> String {
+        format!("{} {}", self.source, self.id)
+    }
+}
+
+/// A payload as posted to `/events/update`: either a plain notification or a CloudEvent.
+pub enum Incoming {
+    Plain(Notification),
+    CloudEvent(CloudEvent),
+}
+
+impl Incoming {
+    /// Parses a plain notification or a structured mode event.
+    pub fn from_value(value: Value) -> Result<Self, Error> {
+        if value.get("specversion").is_none() {
+            return Ok(Incoming::Plain(serde_json::from_value(value)?));
+        }
+        let event: StructuredEvent = serde_json::from_value(value)?;
+        check_specversion(&event.specversion)?;
+        Ok(Incoming::CloudEvent(CloudEvent {
+            id: event.id,
+            source: event.source,
+            notification: event.data,
+        }))
+    }
+
+    /// Parses a binary mode event if the request carries `ce-*` headers.
+    pub fn from_binary(req: &HttpRequest, data: &Value) -> Option<Result<Self, Error>> {
+        let specversion = header(req, "ce-specversion")?;
+        Some(binary_event(req, specversion, data).map(Incoming::CloudEvent))
+    }
+
+    pub fn into_notification(self) -> Notification {
+        match self {
+            Incoming::Plain(n) => n,
+            Incoming::CloudEvent(e) => e.notification,
+        }
+    }
+}
+
+fn binary_event(req: &HttpRequest, specversion: &str, data: &Value) -> Result<CloudEvent, Error> {
+    check_specversion(specversion)?;
+    let required = |name| header(req, name).ok_or_else(|| format_err!("missing {} header", name));
+    let id = required("ce-id")?.to_owned();
+    let source = required("ce-source")?.to_owned();
+    required("ce-type")?;
+    Ok(CloudEvent {
+        id,
+        source,
+        notification: serde_json::from_value(data.clone())?,
+    })
+}
+
+fn check_specversion(specversion: &str) -> Result<(), Error> {
+    if specversion == SPECVERSION {
+        Ok(())
+    } else {
+        Err(format_err!("unsupported specversion {}", specversion))
+    }
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Remembers the most recent event ids so redelivered events are only enqueued once.
+pub struct Dedup {
+    seen: Mutex<(HashSet<String>, VecDeque<String>)>,
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Dedup {
+            seen: Mutex::new((
+                HashSet::with_capacity(DEDUP_CAPACITY),
+                VecDeque::with_capacity(DEDUP_CAPACITY),
+            )),
+        }
+    }
+}
+
+impl Dedup {
+    /// Returns false if the event was seen before. Plain notifications are never duplicates.
+    pub fn first_seen(&self, incoming: &Incoming) -> bool {
+        let key = match incoming {
+            Incoming::Plain(_) => return true,
+            Incoming::CloudEvent(e) => e.key(),
+        };
+        let mut seen = self.seen.lock().unwrap();
+        let (ids, order) = &mut *seen;
+        if !ids.insert(key.clone()) {
+            return false;
+        }
+        order.push_back(key);
+        if order.len() > DEDUP_CAPACITY {
+            if let Some(oldest) = order.pop_front() {
+                ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    #[test]
+    fn test_binary_and_structured_events_dedup() {
+        let data =
+            json!({ "operation": "delete", "id": "ad|Mozilla-LDAP|hknall", "time": 1551963674.0 });
+        let req = TestRequest::default()
+            .insert_header(("ce-specversion", "1.0"))
+            .insert_header(("ce-id", "42"))
+            .insert_header(("ce-source", "cis"))
+            .insert_header(("ce-type", "profile.changed"))
+            .to_http_request();
+        let binary = Incoming::from_binary(&req, &data).unwrap().unwrap();
+        let structured = Incoming::from_value(json!({
+            "specversion": "1.0",
+            "id": "42",
+            "source": "cis",
+            "type": "profile.changed",
+            "data": data,
+        }))
+        .unwrap();
+        let dedup = Dedup::default();
+        assert!(dedup.first_seen(&binary));
+        assert!(!dedup.first_seen(&structured));
+        assert_eq!(structured.into_notification().id, "ad|Mozilla-LDAP|hknall");
+
+        let plain = TestRequest::default().to_http_request();
+        assert!(Incoming::from_binary(&plain, &data).is_none());
+    }
+}
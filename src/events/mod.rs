@@ -1 +1,4 @@
 pub mod app;
+pub mod cloudevents;
+pub mod sns;
+pub mod sqs;
@@ -0,0 +1,313 @@
+//! AWS SNS HTTP(S) subscriptions, see
+//! https://docs.aws.amazon.com/sns/latest/dg/sns-verify-signature-of-message.html
+use crate::context::EventContext;
+use crate::events::app::enqueue;
+use crate::events::app::in_event_span;
+use crate::notification::Notification;
+use crate::settings::SnsCertificateSource;
+use crate::settings::SnsSettings;
+use crate::updater::UpdaterClient;
+use actix_web::dev::HttpServiceFactory;
+use actix_web::error;
+use actix_web::web;
+use actix_web::web::Bytes;
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use actix_web::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use failure::bail;
+use failure::format_err;
+use failure::Error;
+use openssl::hash::MessageDigest;
+use openssl::sign::Verifier;
+use openssl::x509::X509;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use url::Url;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct SnsMessage {
+    #[serde(rename = "Type")]
+    pub kind: String,
+    pub message_id: String,
+    pub token: Option<String>,
+    pub topic_arn: String,
+    pub subject: Option<String>,
+    pub message: String,
+    #[serde(rename = "SubscribeURL")]
+    pub subscribe_url: Option<String>,
+    pub timestamp: String,
+    pub signature_version: String,
+    pub signature: String,
+    #[serde(rename = "SigningCertURL")]
+    pub signing_cert_url: String,
+}
+
+impl SnsMessage {
+    /// The canonical form SNS signs, depending on the message type.
+    fn string_to_sign(&self) -> Result<String, Error> {
+        let fields = match self.kind.as_str() {
+            "Notification" => vec![
+                ("Message", Some(self.message.as_str())),
+                ("MessageId", Some(self.message_id.as_str())),
+                ("Subject", self.subject.as_deref()),
+                ("Timestamp", Some(self.timestamp.as_str())),
+                ("TopicArn", Some(self.topic_arn.as_str())),
+                ("Type", Some(self.kind.as_str())),
+            ],
+            "SubscriptionConfirmation" | "UnsubscribeConfirmation" => vec![
+                ("Message", Some(self.message.as_str())),
+                ("MessageId", Some(self.message_id.as_str())),
+                ("SubscribeURL", self.subscribe_url.as_deref()),
+                ("Timestamp", Some(self.timestamp.as_str())),
+                ("Token", self.token.as_deref()),
+                ("TopicArn", Some(self.topic_arn.as_str())),
+                ("Type", Some(self.kind.as_str())),
+            ],
+            kind => bail!("unknown message type {}", kind),
+        };
+        Ok(fields
+            .into_iter()
+            .filter_map(|(k, v)| v.map(|v| format!("{}\n{}\n", k, v)))
+            .collect())
+    }
+}
+
+/// Checks topics and signatures of SNS messages, caching fetched certificates.
+pub struct SnsVerifier {
+    settings: SnsSettings,
+    client: reqwest::Client,
+    certificates: Mutex<HashMap<String, X509>>,
+}
+
+impl SnsVerifier {
+    pub fn new(settings: SnsSettings) -> Self {
+        SnsVerifier {
+            settings,
+            client: reqwest::Client::new(),
+            certificates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Only https URLs on SNS endpoints are fetched, certificates also have to be `.pem` files.
+    /// A local stand-in signing with a certificate file is trusted with any URL.
+    fn check_url(&self, url: &str, certificate: bool) -> Result<(), Error> {
+        if let SnsCertificateSource::Url = self.settings.certificate {
+            let url = Url::parse(url)?;
+            if url.scheme() != "https"
+                || !is_sns_host(url.host_str().unwrap_or_default())
+                || (certificate && !url.path().ends_with(".pem"))
+            {
+                bail!("untrusted url {}", url);
+            }
+        }
+        Ok(())
+    }
+
+    async fn certificate(&self, msg: &SnsMessage) -> Result<X509, Error> {
+        let location = match &self.settings.certificate {
+            SnsCertificateSource::Url => msg.signing_cert_url.as_str(),
+            SnsCertificateSource::File { path } => path.as_str(),
+        };
+        if let Some(cert) = self.certificates.lock().unwrap().get(location) {
+            return Ok(cert.clone());
+        }
+        let pem = match &self.settings.certificate {
+            SnsCertificateSource::Url => {
+                self.check_url(location, true)?;
+                self.client
+                    .get(location)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?
+                    .to_vec()
+            }
+            SnsCertificateSource::File { path } => fs::read(path)?,
+        };
+        let cert = X509::from_pem(&pem)?;
+        self.certificates
+            .lock()
+            .unwrap()
+            .insert(location.to_owned(), cert.clone());
+        Ok(cert)
+    }
+
+    pub async fn verify(&self, msg: &SnsMessage) -> Result<(), Error> {
+        if !self.settings.topic_arns.contains(&msg.topic_arn) {
+            bail!("topic {} is not allowed", msg.topic_arn);
+        }
+        let digest = match msg.signature_version.as_str() {
+            "1" => MessageDigest::sha1(),
+            "2" => MessageDigest::sha256(),
+            version => bail!("unsupported signature version {}", version),
+        };
+        let signature = STANDARD.decode(&msg.signature)?;
+        let key = self.certificate(msg).await?.public_key()?;
+        let mut verifier = Verifier::new(digest, &key)?;
+        verifier.update(msg.string_to_sign()?.as_bytes())?;
+        if !verifier.verify(&signature)? {
+            bail!("invalid signature");
+        }
+        Ok(())
+    }
+
+    async fn confirm(&self, msg: &SnsMessage) -> Result<(), Error> {
+        let url = msg
+            .subscribe_url
+            .as_deref()
+            .ok_or_else(|| format_err!("missing SubscribeURL"))?;
+        self.check_url(url, false)?;
+        self.client.get(url).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Matches `sns.<region>.amazonaws.com` and `sns.<region>.amazonaws.com.cn`.
+fn is_sns_host(host: &str) -> bool {
+    let region = host.strip_prefix("sns.").and_then(|host| {
+        host.strip_suffix(".amazonaws.com")
+            .or_else(|| host.strip_suffix(".amazonaws.com.cn"))
+    });
+    matches!(region, Some(region) if !region.is_empty()
+        && region.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-'))
+}
+
+async fn sns_event<U: UpdaterClient + Clone + 'static>(
+    req: HttpRequest,
+    updater: Data<U>,
+    verifier: Data<SnsVerifier>,
+    body: Bytes,
+) -> Result<HttpResponse> {
+    let mut ctx = EventContext::from_request(&req);
+    // The message id becomes the correlation id, a body without one gets no span.
+    let msg: SnsMessage = serde_json::from_slice(&body).map_err(error::ErrorBadRequest)?;
+    ctx.correlation_id = msg.message_id.clone();
+    in_event_span(ctx, |ctx| async move {
+        if let Err(e) = verifier.verify(&msg).await {
+            warn!("rejecting sns message {}: {}", msg.message_id, e);
+            return Err(error::ErrorForbidden(e));
+        }
+        match msg.kind.as_str() {
+            "SubscriptionConfirmation" => {
+                verifier.confirm(&msg).await.map_err(|e| {
+                    warn!("unable to confirm subscription to {}: {}", msg.topic_arn, e);
+                    error::ErrorBadGateway(e)
+                })?;
+                info!("subscribed to {}", msg.topic_arn);
+            }
+            "UnsubscribeConfirmation" => info!("unsubscribed from {}", msg.topic_arn),
+            _ => {
+                let n: Notification =
+                    serde_json::from_str(&msg.message).map_err(error::ErrorBadRequest)?;
+                enqueue(&**updater, n, ctx).await;
+            }
+        }
+        Ok(HttpResponse::Ok().finish())
+    })
+    .await
+}
+
+/// SNS cannot send a JWT, messages are authenticated by their signature instead.
+pub fn sns_app<U: UpdaterClient + Clone + Send + 'static>(
+    updater: U,
+    verifier: Data<SnsVerifier>,
+) -> impl HttpServiceFactory {
+    web::scope("/events/sns")
+        .app_data(Data::new(updater))
+        .app_data(verifier)
+        .service(web::resource("").route(web::post().to(sns_event::<U>)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::pkey::PKey;
+    use openssl::sign::Signer;
+
+    const TOPIC: &str = "arn:aws:sns:us-west-2:123456789012:cis-notifications";
+
+    fn signed(message: &str, topic_arn: &str) -> SnsMessage {
+        let mut msg = SnsMessage {
+            kind: String::from("Notification"),
+            message_id: String::from("22b80b92-fdea-4c2c-8f9d-bdfb0c7bf324"),
+            token: None,
+            topic_arn: topic_arn.to_owned(),
+            subject: None,
+            message: message.to_owned(),
+            subscribe_url: None,
+            timestamp: String::from("2019-03-07T13:01:14.000Z"),
+            signature_version: String::from("2"),
+            signature: String::new(),
+            signing_cert_url: String::from("https://sns.local/cert.pem"),
+        };
+        let key = PKey::private_key_from_pem(include_bytes!("../../tests/data/sns-test-key.pem"))
+            .unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer
+            .update(msg.string_to_sign().unwrap().as_bytes())
+            .unwrap();
+        msg.signature = STANDARD.encode(signer.sign_to_vec().unwrap());
+        msg
+    }
+
+    #[actix_rt::test]
+    async fn test_verify_signed_messages() {
+        let verifier = SnsVerifier::new(SnsSettings {
+            enabled: true,
+            topic_arns: vec![String::from(TOPIC)],
+            certificate: SnsCertificateSource::File {
+                path: String::from("tests/data/sns-test-cert.pem"),
+            },
+        });
+        let message = include_str!("../../tests/data/notification.json");
+        assert!(verifier.verify(&signed(message, TOPIC)).await.is_ok());
+
+        let mut tampered = signed(message, TOPIC);
+        tampered.message = tampered.message.replace("update", "delete");
+        assert!(verifier.verify(&tampered).await.is_err());
+
+        let other_topic = signed(message, "arn:aws:sns:us-west-2:123456789012:other");
+        assert!(verifier.verify(&other_topic).await.is_err());
+    }
+
+    #[test]
+    fn test_only_sns_urls_are_trusted() {
+        let verifier = SnsVerifier::new(SnsSettings::default());
+        let cert = "https://sns.us-west-2.amazonaws.com/SimpleNotificationService-0123.pem";
+        assert!(verifier.check_url(cert, true).is_ok());
+        assert!(verifier
+            .check_url("https://sns.cn-north-1.amazonaws.com.cn/cert.pem", true)
+            .is_ok());
+        for untrusted in [
+            "https://evil.s3.amazonaws.com/x.pem",
+            "https://sns.us-west-2.amazonaws.com.evil.com/x.pem",
+            "https://sns..amazonaws.com/x.pem",
+            "http://sns.us-west-2.amazonaws.com/x.pem",
+            "https://sns.us-west-2.amazonaws.com/x.txt",
+        ] {
+            assert!(
+                verifier.check_url(untrusted, true).is_err(),
+                "{}",
+                untrusted
+            );
+        }
+        assert!(verifier
+            .check_url(
+                "https://sns.us-west-2.amazonaws.com/?Action=ConfirmSubscription",
+                false
+            )
+            .is_ok());
+        assert!(verifier
+            .check_url(
+                "https://evil.s3.amazonaws.com/?Action=ConfirmSubscription",
+                false
+            )
+            .is_err());
+    }
+}
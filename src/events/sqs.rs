@@ -0,0 +1,400 @@
+//! Consumes notifications from an SQS compatible queue. Unlike the web hooks no events are lost
+//! while lookout is down: a message is only deleted once the updater processed it successfully.
+use crate::context::EventContext;
+use crate::events::cloudevents::Incoming;
+use crate::settings::SqsSettings;
+use crate::updater::UpdaterClient;
+use aws_config::BehaviorVersion;
+use aws_config::Region;
+use aws_sdk_sqs::Client;
+use failure::format_err;
+use failure::Error;
+use futures::channel::oneshot;
+use std::cmp;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
+use tokio::task;
+use tokio::task::LocalSet;
+
+/// Waited at least after a failed poll, even without long polling.
+const POLL_BACKOFF: Duration = Duration::from_secs(5);
+
+/// A received message with what the consumer needs of it.
+pub struct QueueMessage {
+    pub message_id: Option<String>,
+    pub receipt_handle: String,
+    pub body: String,
+}
+
+/// The queue operations the consumer uses, implemented for SQS.
+pub trait Queue {
+    /// Receives at most `max_messages`.
+    async fn receive(
+        &self,
+        settings: &SqsSettings,
+        max_messages: i32,
+    ) -> Result<Vec<QueueMessage>, Error>;
+    async fn delete(&self, receipt_handle: &str) -> Result<(), Error>;
+    async fn change_visibility(&self, receipt_handle: &str, seconds: i32) -> Result<(), Error>;
+}
+
+pub struct SqsQueue {
+    client: Client,
+    queue_url: String,
+}
+
+impl SqsQueue {
+    pub async fn new(settings: &SqsSettings) -> Self {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(endpoint) = &settings.endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+        if let Some(region) = &settings.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        let config = loader.load().await;
+        SqsQueue {
+            client: Client::new(&config),
+            queue_url: settings.queue_url.clone(),
+        }
+    }
+}
+
+impl Queue for SqsQueue {
+    async fn receive(
+        &self,
+        settings: &SqsSettings,
+        max_messages: i32,
+    ) -> Result<Vec<QueueMessage>, Error> {
+        let out = self
+            .client
+            .receive_message()
+            .queue_url(&self.queue_url)
+            .max_number_of_messages(max_messages)
+            .wait_time_seconds(settings.wait_time)
+            .visibility_timeout(settings.visibility_timeout)
+            .send()
+            .await
+            .map_err(aws_sdk_sqs::Error::from)?;
+        Ok(out
+            .messages()
+            .iter()
+            .filter_map(|msg| match msg.receipt_handle() {
+                Some(receipt_handle) => Some(QueueMessage {
+                    message_id: msg.message_id().map(ToOwned::to_owned),
+                    receipt_handle: receipt_handle.to_owned(),
+                    body: msg.body().unwrap_or_default().to_owned(),
+                }),
+                None => {
+                    warn!("skipping message without receipt handle");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    async fn delete(&self, receipt_handle: &str) -> Result<(), Error> {
+        self.client
+            .delete_message()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt_handle)
+            .send()
+            .await
+            .map_err(aws_sdk_sqs::Error::from)?;
+        Ok(())
+    }
+
+    async fn change_visibility(&self, receipt_handle: &str, seconds: i32) -> Result<(), Error> {
+        self.client
+            .change_message_visibility()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt_handle)
+            .visibility_timeout(seconds)
+            .send()
+            .await
+            .map_err(aws_sdk_sqs::Error::from)?;
+        Ok(())
+    }
+}
+
+pub struct SqsConsumer<Q: Queue, U: UpdaterClient> {
+    queue: Q,
+    settings: SqsSettings,
+    updater: U,
+    /// One permit per message in flight.
+    in_flight: Arc<Semaphore>,
+}
+
+impl<Q: Queue + 'static, U: UpdaterClient + 'static> SqsConsumer<Q, U> {
+    pub fn new(queue: Q, settings: SqsSettings, updater: U) -> Self {
+        let in_flight = Arc::new(Semaphore::new(settings.max_in_flight.max(1)));
+        SqsConsumer {
+            queue,
+            settings,
+            updater,
+            in_flight,
+        }
+    }
+
+    /// Handles every message on its own local task, so messages kept in flight for a scheduled
+    /// delete do not hold up polling. Only receives as many messages as may still be in
+    /// flight and waits while `max_in_flight` are. Has to run inside a `LocalSet`.
+    pub async fn run(self: Rc<Self>) {
+        info!("consuming notifications from {}", self.settings.queue_url);
+        let max_messages = self.settings.max_messages.max(1) as usize;
+        loop {
+            let first = Arc::clone(&self.in_flight)
+                .acquire_owned()
+                .await
+                .expect("in flight semaphore is never closed");
+            let mut permits = vec![first];
+            while permits.len() < max_messages {
+                match Arc::clone(&self.in_flight).try_acquire_owned() {
+                    Ok(permit) => permits.push(permit),
+                    Err(_) => break,
+                }
+            }
+            match self
+                .queue
+                .receive(&self.settings, permits.len() as i32)
+                .await
+            {
+                Ok(messages) => {
+                    for (msg, permit) in messages.into_iter().zip(permits) {
+                        let consumer = Rc::clone(&self);
+                        task::spawn_local(async move {
+                            if let Err(e) = consumer.handle(msg).await {
+                                warn!("unable to handle message: {}", e);
+                            }
+                            drop(permit);
+                        });
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "unable to receive messages from {}: {}",
+                        self.settings.queue_url, e
+                    );
+                    let wait_time = Duration::from_secs(self.settings.wait_time.max(0) as u64);
+                    tokio::time::sleep(cmp::max(wait_time, POLL_BACKOFF)).await;
+                }
+            }
+        }
+    }
+
+    /// Enqueues the notification and deletes the message once it was processed. Failed and
+    /// malformed messages are left on the queue for its redrive policy.
+    async fn handle(&self, msg: QueueMessage) -> Result<(), Error> {
+        let n = serde_json::from_str(&msg.body)
+            .map_err(Error::from)
+            .and_then(Incoming::from_value)?
+            .into_notification();
+        let ctx = match msg.message_id {
+            Some(id) => EventContext {
+                correlation_id: id,
+                ..Default::default()
+            },
+            None => EventContext::default(),
+        };
+        let correlation_id = ctx.correlation_id.clone();
+        ctx.scope(async { info!("received {:?} for {}", n.operation, n.id) })
+            .await;
+        let acked = self.updater.update_acked(n, ctx);
+        if self.keep_in_flight(&msg.receipt_handle, acked).await {
+            self.queue.delete(&msg.receipt_handle).await
+        } else {
+            self.queue
+                .change_visibility(&msg.receipt_handle, self.settings.retry_delay)
+                .await?;
+            Err(format_err!(
+                "failed to process message ({})",
+                correlation_id
+            ))
+        }
+    }
+
+    /// Waits for the updater while extending the message's visibility every half
+    /// `visibility_timeout`, so it is not delivered again while it waits in the updater queue
+    /// or for the grace period of a delete. If lookout stops, the message becomes visible again.
+    async fn keep_in_flight(
+        &self,
+        receipt_handle: &str,
+        mut acked: oneshot::Receiver<bool>,
+    ) -> bool {
+        let visibility_timeout = self.settings.visibility_timeout.max(1);
+        let period = Duration::from_millis(visibility_timeout as u64 * 500);
+        loop {
+            match tokio::time::timeout(period, &mut acked).await {
+                Ok(res) => return res.unwrap_or(false),
+                Err(_) => {
+                    if let Err(e) = self
+                        .queue
+                        .change_visibility(receipt_handle, visibility_timeout)
+                        .await
+                    {
+                        warn!("unable to extend visibility of message: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs the consumer on its own runtime until the process exits. Messages in flight when
+/// lookout stops are delivered again.
+pub fn consume<U: UpdaterClient + 'static>(settings: SqsSettings, updater: U) -> Result<(), Error> {
+    let rt = Runtime::new()?;
+    LocalSet::new().block_on(&rt, async {
+        let queue = SqsQueue::new(&settings).await;
+        Rc::new(SqsConsumer::new(queue, settings, updater))
+            .run()
+            .await;
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bulk::Bulk;
+    use crate::notification::Notification;
+    use crate::updater::Ack;
+    use futures::future::join;
+    use std::sync::Mutex;
+
+    /// Hands out `waiting` and records what the consumer does with its messages.
+    #[derive(Default)]
+    struct StandInQueue {
+        waiting: Mutex<Vec<QueueMessage>>,
+        requested: Mutex<Vec<i32>>,
+        deleted: Mutex<Vec<String>>,
+        visibility: Mutex<Vec<(String, i32)>>,
+    }
+
+    impl Queue for StandInQueue {
+        async fn receive(
+            &self,
+            _: &SqsSettings,
+            max_messages: i32,
+        ) -> Result<Vec<QueueMessage>, Error> {
+            self.requested.lock().unwrap().push(max_messages);
+            let messages: Vec<_> = {
+                let mut waiting = self.waiting.lock().unwrap();
+                let n = waiting.len().min(max_messages as usize);
+                waiting.drain(..n).collect()
+            };
+            if messages.is_empty() {
+                // long polling
+                actix_rt::time::sleep(Duration::from_millis(50)).await;
+            }
+            Ok(messages)
+        }
+
+        async fn delete(&self, receipt_handle: &str) -> Result<(), Error> {
+            self.deleted.lock().unwrap().push(receipt_handle.to_owned());
+            Ok(())
+        }
+
+        async fn change_visibility(&self, receipt_handle: &str, seconds: i32) -> Result<(), Error> {
+            self.visibility
+                .lock()
+                .unwrap()
+                .push((receipt_handle.to_owned(), seconds));
+            Ok(())
+        }
+    }
+
+    /// Hands the acks to the test instead of processing anything.
+    #[derive(Default)]
+    struct StandInUpdater {
+        acks: Mutex<Vec<Ack>>,
+    }
+
+    impl UpdaterClient for StandInUpdater {
+        fn update(&self, _: Notification, _: EventContext) {}
+        fn update_acked(&self, _: Notification, _: EventContext) -> oneshot::Receiver<bool> {
+            let (ack, acked) = oneshot::channel();
+            self.acks.lock().unwrap().push(ack);
+            acked
+        }
+        fn update_all(&self, _: Bulk) {}
+        fn stop(&self) {}
+    }
+
+    fn message(receipt_handle: &str) -> QueueMessage {
+        QueueMessage {
+            message_id: Some(String::from("22b80b92-fdea-4c2c-8f9d-bdfb0c7bf324")),
+            receipt_handle: receipt_handle.to_owned(),
+            body: include_str!("../../tests/data/notification.json").to_owned(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_messages_stay_in_flight_until_processed() {
+        let consumer = SqsConsumer::new(
+            StandInQueue::default(),
+            SqsSettings {
+                visibility_timeout: 1,
+                retry_delay: 60,
+                ..Default::default()
+            },
+            StandInUpdater::default(),
+        );
+        // e.g. a delete waiting for its grace period
+        let updater = &consumer.updater;
+        let ack_later = |ok: Option<bool>| async move {
+            actix_rt::time::sleep(Duration::from_millis(1200)).await;
+            let ack = updater.acks.lock().unwrap().pop().unwrap();
+            if let Some(ok) = ok {
+                ack.send(ok).unwrap();
+            }
+        };
+        let (res, _) = join(consumer.handle(message("done")), ack_later(Some(true))).await;
+        assert!(res.is_ok());
+        assert_eq!(*consumer.queue.deleted.lock().unwrap(), vec!["done"]);
+        assert_eq!(
+            *consumer.queue.visibility.lock().unwrap(),
+            vec![(String::from("done"), 1), (String::from("done"), 1)]
+        );
+
+        // lookout stopped before the delete was executed
+        consumer.queue.visibility.lock().unwrap().clear();
+        let (res, _) = join(consumer.handle(message("dropped")), ack_later(None)).await;
+        assert!(res.is_err());
+        assert_eq!(consumer.queue.deleted.lock().unwrap().len(), 1);
+        assert_eq!(
+            consumer.queue.visibility.lock().unwrap().last(),
+            Some(&(String::from("dropped"), 60))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_receiving_waits_at_max_in_flight() {
+        let queue = StandInQueue {
+            waiting: Mutex::new(vec![message("a"), message("b"), message("c")]),
+            ..Default::default()
+        };
+        let settings = SqsSettings {
+            max_in_flight: 2,
+            ..Default::default()
+        };
+        let consumer = Rc::new(SqsConsumer::new(queue, settings, StandInUpdater::default()));
+        let running = task::spawn_local(Rc::clone(&consumer).run());
+        let settle = || actix_rt::time::sleep(Duration::from_millis(200));
+
+        settle().await;
+        assert_eq!(*consumer.queue.requested.lock().unwrap(), vec![2]);
+        assert_eq!(consumer.updater.acks.lock().unwrap().len(), 2);
+
+        let ack = consumer.updater.acks.lock().unwrap().remove(0);
+        ack.send(true).unwrap();
+        settle().await;
+        assert_eq!(*consumer.queue.requested.lock().unwrap(), vec![2, 1]);
+        assert_eq!(*consumer.queue.deleted.lock().unwrap(), vec!["a"]);
+        assert_eq!(consumer.updater.acks.lock().unwrap().len(), 2);
+        running.abort();
+    }
+}
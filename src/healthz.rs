@@ -1,12 +1,133 @@
+use crate::readyz::fetch_cis_token;
+use crate::settings::DiagnosticsSettings;
+use crate::settings::DinoParkSettings;
 use actix_web::dev::HttpServiceFactory;
 use actix_web::web;
+use actix_web::web::Data;
 use actix_web::HttpRequest;
 use actix_web::HttpResponse;
+use cis_client::settings::CisSettings;
+use failure::Error;
+use futures::future::join;
+use futures::future::join_all;
+use reqwest::Client;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+use std::future::Future;
+use std::time::Duration;
+use std::time::Instant;
+use url::Url;
+
+pub struct Diagnostics {
+    settings: DiagnosticsSettings,
+    cis_settings: CisSettings,
+    services: Vec<(&'static str, String)>,
+}
+
+impl Diagnostics {
+    pub fn new(
+        settings: DiagnosticsSettings,
+        cis_settings: CisSettings,
+        dp: &DinoParkSettings,
+    ) -> Self {
+        let mut services = vec![
+            ("orgchart", dp.orgchart_update_endpoint.clone()),
+            ("search", dp.search_update_endpoint.clone()),
+            ("pictures", dp.picture_delete_endpoint.clone()),
+            ("uuid_lookup", dp.uuid_by_user_id_endpoint.clone()),
+        ];
+        if let Some(ref groups_update_endpoint) = dp.groups_update_endpoint {
+            services.push(("groups", groups_update_endpoint.clone()));
+        }
+        Diagnostics {
+            settings,
+            cis_settings,
+            services,
+        }
+    }
+
+    fn health_url(&self, endpoint: &str) -> Result<Url, Error> {
+        Ok(Url::parse(endpoint)?.join(&self.settings.health_path)?)
+    }
+
+    async fn probe(&self, client: &Client, endpoint: &str) -> Value {
+        let url = match self.health_url(endpoint) {
+            Ok(url) => url,
+            Err(e) => return json!({ "ok": false, "error": e.to_string() }),
+        };
+        let (res, latency) = timed(client.get(url.clone()).send()).await;
+        match res {
+            Ok(res) => json!({
+                "ok": res.status().is_success(),
+                "url": url.as_str(),
+                "status": res.status().as_u16(),
+                "latency_ms": latency,
+            }),
+            Err(e) => json!({
+                "ok": false,
+                "url": url.as_str(),
+                "error": e.to_string(),
+                "latency_ms": latency,
+            }),
+        }
+    }
+}
+
+async fn timed<F: Future>(f: F) -> (F::Output, u128) {
+    let start = Instant::now();
+    let out = f.await;
+    (out, start.elapsed().as_millis())
+}
 
 async fn healthz(_: HttpRequest) -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
-pub fn healthz_app() -> impl HttpServiceFactory {
-    web::scope("/healthz").service(web::resource("").to(healthz))
+async fn deep_healthz(diagnostics: Data<Diagnostics>) -> HttpResponse {
+    let client = match Client::builder()
+        .timeout(Duration::from_millis(diagnostics.settings.timeout_ms))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(json!({ "error": e.to_string() }))
+        }
+    };
+    let probes = join_all(
+        diagnostics
+            .services
+            .iter()
+            .map(|(_, endpoint)| diagnostics.probe(&client, endpoint)),
+    );
+    let token = timed(fetch_cis_token(&diagnostics.cis_settings));
+    let (probes, (token, token_latency)) = join(probes, token).await;
+
+    let mut ok = token.is_ok();
+    let mut services = Map::new();
+    for ((name, _), probe) in diagnostics.services.iter().zip(probes) {
+        ok &= probe["ok"] == json!(true);
+        services.insert(String::from(*name), probe);
+    }
+    let body = json!({
+        "ok": ok,
+        "cis_token": {
+            "ok": token.is_ok(),
+            "error": token.err().map(|e| e.to_string()),
+            "latency_ms": token_latency,
+        },
+        "services": services,
+    });
+    if ok {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+pub fn healthz_app(diagnostics: Data<Diagnostics>) -> impl HttpServiceFactory {
+    web::scope("/healthz")
+        .app_data(diagnostics)
+        .service(web::resource("").to(healthz))
+        .service(web::resource("/deep").to(deep_healthz))
 }
@@ -1,6 +1,19 @@
+use crate::audit::AuditEntry;
+use crate::audit::AuditLog;
+use crate::audit::AuditQuery;
 use crate::bulk::Bulk;
+use crate::cis::PersonApi;
+use crate::context::EventContext;
+use crate::error::ProfileNotFound;
+use crate::error::UnverifiedProfile;
+use crate::lookup::Lookup;
+use crate::metrics;
+use crate::pending::PendingDeletes;
 use crate::settings::DinoParkSettings;
+use crate::status::UpdaterStatus;
+use crate::updater;
 use crate::updater::send_profile;
+use crate::updater::Deletion;
 use crate::updater::UpdaterClient;
 use actix_web::dev::HttpServiceFactory;
 use actix_web::error;
@@ -8,8 +21,12 @@ use actix_web::error::Error;
 use actix_web::web;
 use actix_web::web::Data;
 use actix_web::web::Json;
+use actix_web::web::Path;
+use actix_web::web::Query;
+use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::Result;
+use chrono::Utc;
 use cis_profile::schema::Profile;
 use futures::future::TryFutureExt;
 use serde_json::json;
@@ -17,11 +34,15 @@ use serde_json::Value;
 
 pub async fn internal_update(dp: &DinoParkSettings, profile: Profile) -> Result<Value, Error> {
     send_profile(dp, profile)
-        .map_err(error::ErrorInternalServerError)
+        .map_err(|e| match e.downcast::<UnverifiedProfile>() {
+            Ok(e) => error::ErrorUnprocessableEntity(e),
+            Err(e) => error::ErrorInternalServerError(e),
+        })
         .await
 }
 
 async fn internal_update_event(
+    req: HttpRequest,
     dino_park_settings: Data<DinoParkSettings>,
     profile: Json<Profile>,
 ) -> Result<HttpResponse, Error> {
@@ -30,17 +51,21 @@ async fn internal_update_event(
         .value
         .clone()
         .unwrap_or_else(|| String::from("unknown"));
-    info!("internally updating profile for: {}", &id);
-    let id_c = id.clone();
-    let res = internal_update(&dino_park_settings, profile.into_inner()).await;
-    info!("internally updated profile for {}", id);
-    match res {
-        Ok(j) => Ok(HttpResponse::Ok().json(j)),
-        Err(e) => {
-            error!("failed to internally update profile for {}: {}", id_c, e);
-            Err(error::ErrorInternalServerError(e))
+    let ctx = EventContext::from_request(&req);
+    ctx.scope(async {
+        info!("internally updating profile for: {}", &id);
+        let id_c = id.clone();
+        let res = internal_update(&dino_park_settings, profile.into_inner()).await;
+        info!("internally updated profile for {}", id);
+        match res {
+            Ok(j) => Ok(HttpResponse::Ok().json(j)),
+            Err(e) => {
+                error!("failed to internally update profile for {}: {}", id_c, e);
+                Err(e)
+            }
         }
-    }
+    })
+    .await
 }
 
 async fn bulk_update<U: UpdaterClient + Clone + 'static>(
@@ -51,14 +76,215 @@ async fn bulk_update<U: UpdaterClient + Clone + 'static>(
     Ok(HttpResponse::Ok().json(json!({})))
 }
 
+#[derive(Deserialize)]
+struct QueueQuery {
+    limit: Option<usize>,
+}
+
+async fn queue(status: Data<UpdaterStatus>, query: Query<QueueQuery>) -> HttpResponse {
+    HttpResponse::Ok().json(status.snapshot(query.limit.unwrap_or(20)))
+}
+
+async fn audit(audit: Data<AuditLog>, query: Query<AuditQuery>) -> Result<HttpResponse> {
+    let entries = audit
+        .query(&query)
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+async fn pending_deletes(pending: Data<PendingDeletes>) -> HttpResponse {
+    HttpResponse::Ok().json(pending.list())
+}
+
+async fn cancel_delete(
+    req: HttpRequest,
+    pending: Data<PendingDeletes>,
+    audit: Data<AuditLog>,
+    user_id: Path<String>,
+) -> HttpResponse {
+    match pending.cancel(&user_id) {
+        Some(cancelled) => {
+            info!("cancelled scheduled delete for {}", user_id.as_str());
+            let mut entry = AuditEntry::skipped(
+                &user_id,
+                Some(&cancelled.notification),
+                Some(&cancelled.correlation_id),
+                cancelled.scheduled_at,
+            );
+            entry.warning = Some(format!(
+                "cancelled internally ({})",
+                EventContext::from_request(&req).correlation_id
+            ));
+            audit.record(&entry);
+            HttpResponse::Ok().json(cancelled)
+        }
+        None => HttpResponse::NotFound().json(json!({ "error": "no scheduled delete" })),
+    }
+}
+
+#[derive(Deserialize)]
+struct LookupQuery {
+    #[serde(default)]
+    by: Lookup,
+}
+
+async fn resync(
+    req: HttpRequest,
+    person_api: Data<PersonApi>,
+    dino_park_settings: Data<DinoParkSettings>,
+    id: Path<String>,
+    query: Query<LookupQuery>,
+) -> Result<HttpResponse> {
+    let ctx = EventContext::from_request(&req);
+    ctx.scope(async {
+        info!("resyncing profile for {:?}: {}", query.by, id.as_str());
+        let update = updater::resync(&person_api, &dino_park_settings, &id, query.by)
+            .await
+            .map_err(|e| {
+                warn!("unable to resync profile for {}: {}", id.as_str(), e);
+                lookup_error(e)
+            })?;
+        if update.is_ok() {
+            Ok(HttpResponse::Ok().json(update))
+        } else {
+            Ok(HttpResponse::BadGateway().json(update))
+        }
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    #[serde(default)]
+    confirm: bool,
+    #[serde(default)]
+    by: Lookup,
+}
+
+fn deletion_response(res: Result<Deletion, failure::Error>) -> Result<HttpResponse> {
+    match res {
+        Ok(deletion) if deletion.is_ok() => Ok(HttpResponse::Ok().json(deletion)),
+        Ok(deletion) => Ok(HttpResponse::BadGateway().json(deletion)),
+        Err(e) => Err(lookup_error(e)),
+    }
+}
+
+/// Unknown profiles are the caller's problem, anything else is CIS's or DinoPark's.
+fn lookup_error(e: failure::Error) -> actix_web::Error {
+    match e.downcast::<ProfileNotFound>() {
+        Ok(e) => error::ErrorNotFound(e),
+        Err(e) => error::ErrorBadGateway(e),
+    }
+}
+
+async fn execute_delete(
+    req: &HttpRequest,
+    person_api: &PersonApi,
+    dino_park_settings: &DinoParkSettings,
+    audit: &AuditLog,
+    id: &str,
+    lookup: Lookup,
+    confirm: bool,
+) -> Result<HttpResponse> {
+    if !confirm {
+        return Err(error::ErrorBadRequest("deleting requires ?confirm=true"));
+    }
+    let ctx = EventContext::from_request(req);
+    ctx.scope(async {
+        info!("internally deleting profile for {:?}: {}", lookup, id);
+        let started_at = Utc::now();
+        let (user_id, res) =
+            match updater::delete_target(person_api, dino_park_settings, id, lookup).await {
+                Ok(target) => {
+                    let user_id = target.user_id.unwrap_or_else(|| String::from("unknown"));
+                    let deletion =
+                        updater::delete_uuid(dino_park_settings, &user_id, target.uuid).await;
+                    (user_id, Ok(deletion))
+                }
+                Err(e) => (id.to_owned(), Err(e)),
+            };
+        audit.record(&AuditEntry::deletion(
+            &user_id,
+            None,
+            Some(&ctx.correlation_id),
+            started_at,
+            &res,
+        ));
+        deletion_response(res)
+    })
+    .await
+}
+
+async fn delete_profile(
+    req: HttpRequest,
+    person_api: Data<PersonApi>,
+    dino_park_settings: Data<DinoParkSettings>,
+    audit: Data<AuditLog>,
+    id: Path<String>,
+    query: Query<DeleteQuery>,
+) -> Result<HttpResponse> {
+    execute_delete(
+        &req,
+        &person_api,
+        &dino_park_settings,
+        &audit,
+        &id,
+        query.by,
+        query.confirm,
+    )
+    .await
+}
+
+async fn delete_by_uuid(
+    req: HttpRequest,
+    person_api: Data<PersonApi>,
+    dino_park_settings: Data<DinoParkSettings>,
+    audit: Data<AuditLog>,
+    uuid: Path<String>,
+    query: Query<DeleteQuery>,
+) -> Result<HttpResponse> {
+    execute_delete(
+        &req,
+        &person_api,
+        &dino_park_settings,
+        &audit,
+        &uuid,
+        Lookup::Uuid,
+        query.confirm,
+    )
+    .await
+}
+
+async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
 pub fn internal_app<U: UpdaterClient + Clone + Send + 'static>(
     dino_park_settings: DinoParkSettings,
+    person_api: PersonApi,
     updater: U,
+    status: UpdaterStatus,
+    audit_log: AuditLog,
+    pending: PendingDeletes,
 ) -> impl HttpServiceFactory {
     web::scope("")
         .app_data(Data::new(updater))
+        .app_data(Data::new(person_api))
+        .app_data(Data::new(status))
+        .app_data(Data::new(audit_log))
+        .app_data(Data::new(pending))
         .app_data(Data::new(dino_park_settings))
         .app_data(Data::new(web::JsonConfig::default().limit(1_048_576)))
         .service(web::resource("/bulk").route(web::post().to(bulk_update::<U>)))
         .service(web::resource("/update").route(web::post().to(internal_update_event)))
+        .service(web::resource("/resync/{id}").route(web::post().to(resync)))
+        .service(web::resource("/delete/by-uuid/{uuid}").route(web::post().to(delete_by_uuid)))
+        .service(web::resource("/delete/{id}").route(web::post().to(delete_profile)))
+        .service(web::resource("/queue").route(web::get().to(queue)))
+        .service(web::resource("/audit").route(web::get().to(audit)))
+        .service(web::resource("/metrics").route(web::get().to(metrics)))
+        .service(web::resource("/deletes").route(web::get().to(pending_deletes)))
+        .service(web::resource("/deletes/{user_id}").route(web::delete().to(cancel_delete)))
 }
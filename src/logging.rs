@@ -0,0 +1,23 @@
+use crate::context::correlation_id;
+use chrono::SecondsFormat;
+use chrono::Utc;
+use serde_json::json;
+use std::io::Write;
+
+/// Logs one JSON object per line, tagged with the correlation id of the event being processed.
+pub fn init() {
+    env_logger::Builder::from_default_env()
+        .format(|buf, record| {
+            let mut line = json!({
+                "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            if let Some(id) = correlation_id() {
+                line["correlation_id"] = json!(id);
+            }
+            writeln!(buf, "{}", line)
+        })
+        .init();
+}
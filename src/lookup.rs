@@ -0,0 +1,24 @@
+use cis_client::getby::GetBy;
+
+/// How the id of a user is to be interpreted when looking them up in CIS.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum Lookup {
+    #[default]
+    UserId,
+    PrimaryEmail,
+    PrimaryUsername,
+    Uuid,
+}
+
+impl Lookup {
+    pub fn get_by(self) -> GetBy {
+        match self {
+            Lookup::UserId => GetBy::UserId,
+            Lookup::PrimaryEmail => GetBy::PrimaryEmail,
+            Lookup::PrimaryUsername => GetBy::PrimaryUsername,
+            Lookup::Uuid => GetBy::Uuid,
+        }
+    }
+}
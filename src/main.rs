@@ -5,42 +5,89 @@ extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+mod audit;
+mod body;
 mod bulk;
+mod cis;
+mod cli;
+mod context;
 mod error;
 mod events;
 mod healthz;
 mod internal;
+mod logging;
+mod lookup;
+mod metrics;
 mod notification;
+mod payload;
+mod pending;
+mod readyz;
 mod settings;
+mod signature;
+mod sink;
+mod status;
+mod telemetry;
 mod updater;
 
+use crate::audit::AuditLog;
+use crate::cis::PersonApi;
+use crate::cli::Cli;
+use crate::cli::Command;
 use crate::events::app::update_app;
+use crate::events::cloudevents::Dedup;
+use crate::events::sns::sns_app;
+use crate::events::sns::SnsVerifier;
+use crate::events::sqs;
 use crate::healthz::healthz_app;
+use crate::healthz::Diagnostics;
 use crate::internal::app::internal_app;
+use crate::pending::PendingDeletes;
+use crate::readyz::readyz_app;
+use crate::readyz::Readiness;
+use crate::settings::Settings;
 use crate::updater::InternalUpdater;
 use crate::updater::Updater;
 use crate::updater::UpdaterClient;
 use actix_rt::System;
 use actix_web::middleware::Logger;
 use actix_web::web;
+use actix_web::web::Data;
 use actix_web::App;
 use actix_web::HttpServer;
 use cis_client::CisClient;
+use clap::Parser;
 use dino_park_gate::provider::Provider;
 use dino_park_gate::simple::SimpleAuth;
 use failure::format_err;
 use failure::Error;
 use std::thread::spawn;
+use tokio::runtime::Runtime;
 
 fn main() -> Result<(), Error> {
     ::std::env::set_var(
         "RUST_LOG",
         "actix_web=info,dino_park_lookout=info,dino_park_gate=info,cis_client=info,shared_expiry_get=info",
     );
-    env_logger::init();
+    logging::init();
+    let cli = Cli::parse();
+    let s = Settings::new().map_err(|e| format_err!("unable to load settings: {}", e))?;
+    telemetry::init(&s.tracing)?;
+    Runtime::new()?.block_on(signature::init(&s.verification, &s.cis))?;
+    let res = match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(s),
+        Command::Replay(args) => cli::replay::run(s, args),
+        Command::Update(args) => cli::oneshot::update(s, args),
+        Command::Delete(args) => cli::oneshot::delete(s, args),
+        Command::Bulk(args) => cli::oneshot::bulk(s, args),
+        Command::Preview(args) => cli::preview::run(s, args),
+    };
+    telemetry::shutdown();
+    res
+}
+
+fn serve(s: Settings) -> Result<(), Error> {
     info!("building the lookout");
     let rt = System::new();
-    let s = settings::Settings::new().map_err(|e| format_err!("unable to load settings: {}", e))?;
     let cis_settings = s.cis.clone();
     let cis_client = rt.block_on(async move {
         CisClient::from_settings(&cis_settings)
@@ -52,10 +99,46 @@ fn main() -> Result<(), Error> {
     let issuer = s.auth.issuer;
     let provider = rt.block_on(async move { Provider::from_issuer(&issuer).await })?;
     // Start http server
-    let updater = InternalUpdater::new(cis_client, dino_park.clone());
+    let audit_log = AuditLog::new(&s.audit);
+    let person_api = PersonApi::new(s.cis.clone());
+    let internal_person_api = person_api.clone();
+    let updater = InternalUpdater::new(
+        cis_client,
+        person_api,
+        dino_park.clone(),
+        s.updater.clone(),
+        audit_log.clone(),
+        PendingDeletes::load(&s.audit)?,
+    );
 
+    let dedup = Data::new(Dedup::default());
+    let sns_enabled = s.sns.enabled;
+    let sns_verifier = Data::new(SnsVerifier::new(s.sns.clone()));
+    let readiness = Data::new(Readiness::new(
+        updater.status(),
+        s.readiness.clone(),
+        s.cis.clone(),
+        &dino_park,
+    ));
+    let diagnostics = Data::new(Diagnostics::new(
+        s.diagnostics.clone(),
+        s.cis.clone(),
+        &dino_park,
+    ));
+
+    let status = updater.status();
+    let pending = updater.pending_deletes();
     let client = updater.client();
     let stop_client = updater.client();
+    if s.sqs.enabled {
+        let sqs_settings = s.sqs.clone();
+        let sqs_client = updater.client();
+        spawn(move || {
+            if let Err(e) = sqs::consume(sqs_settings, sqs_client) {
+                error!("unable to consume sqs queue: {}", e);
+            }
+        });
+    }
     let updater_thread = spawn(move || {
         if let Err(e) = updater.run() {
             error!("unable to start updater: {}", e);
@@ -68,16 +151,27 @@ fn main() -> Result<(), Error> {
         };
 
         App::new()
-            .wrap(Logger::default().exclude("/healthz"))
-            .service(
-                web::scope("/internal").service(internal_app(dino_park.clone(), client.clone())),
-            )
+            .wrap(Logger::default().exclude("/healthz").exclude("/readyz"))
+            .service(web::scope("/internal").service(internal_app(
+                dino_park.clone(),
+                internal_person_api.clone(),
+                client.clone(),
+                status.clone(),
+                audit_log.clone(),
+                pending.clone(),
+            )))
+            .configure(|cfg| {
+                if sns_enabled {
+                    cfg.service(sns_app(client.clone(), sns_verifier.clone()));
+                }
+            })
             .service(
                 web::scope("/events")
                     .wrap(auth_middleware)
-                    .service(update_app(client.clone())),
+                    .service(update_app(client.clone(), dedup.clone())),
             )
-            .service(healthz_app())
+            .service(healthz_app(diagnostics.clone()))
+            .service(readyz_app(readiness.clone()))
     })
     .bind("0.0.0.0:8082")?;
 
@@ -0,0 +1,117 @@
+use std::fmt::Write;
+use std::fs;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+/// A monotonic counter rendered in the Prometheus text format.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str, labels: &'static str) -> Self {
+        Counter {
+            name,
+            help,
+            labels,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+const UNKNOWN_OPERATIONS: &str = "lookout_unknown_operations_total";
+const UNKNOWN_OPERATIONS_HELP: &str =
+    "Notifications with an unknown operation by policy and decision.";
+
+pub static UNKNOWN_DELETED: Counter = Counter::new(
+    UNKNOWN_OPERATIONS,
+    UNKNOWN_OPERATIONS_HELP,
+    r#"policy="delete",decision="delete""#,
+);
+pub static UNKNOWN_IGNORED: Counter = Counter::new(
+    UNKNOWN_OPERATIONS,
+    UNKNOWN_OPERATIONS_HELP,
+    r#"policy="ignore",decision="ignore""#,
+);
+pub static UNKNOWN_INFERRED_UPDATE: Counter = Counter::new(
+    UNKNOWN_OPERATIONS,
+    UNKNOWN_OPERATIONS_HELP,
+    r#"policy="infer",decision="update""#,
+);
+pub static UNKNOWN_INFERRED_DELETE: Counter = Counter::new(
+    UNKNOWN_OPERATIONS,
+    UNKNOWN_OPERATIONS_HELP,
+    r#"policy="infer",decision="delete""#,
+);
+
+const VERIFICATIONS: &str = "lookout_profile_verifications_total";
+const VERIFICATIONS_HELP: &str = "Profiles checked for publisher signatures by result.";
+
+pub static PROFILES_VERIFIED: Counter =
+    Counter::new(VERIFICATIONS, VERIFICATIONS_HELP, r#"result="verified""#);
+pub static PROFILES_STRIPPED: Counter =
+    Counter::new(VERIFICATIONS, VERIFICATIONS_HELP, r#"result="stripped""#);
+pub static PROFILES_REJECTED: Counter =
+    Counter::new(VERIFICATIONS, VERIFICATIONS_HELP, r#"result="rejected""#);
+
+/// Counters sharing a name must be next to each other.
+static ALL: [&Counter; 7] = [
+    &UNKNOWN_DELETED,
+    &UNKNOWN_IGNORED,
+    &UNKNOWN_INFERRED_UPDATE,
+    &UNKNOWN_INFERRED_DELETE,
+    &PROFILES_VERIFIED,
+    &PROFILES_STRIPPED,
+    &PROFILES_REJECTED,
+];
+
+/// Resets the peak resident set size to the current one, so `peak_memory_kb` reports the peak
+/// since then. Returns whether that worked.
+pub fn reset_peak_memory() -> bool {
+    fs::write("/proc/self/clear_refs", "5").is_ok()
+}
+
+/// Peak resident set size of the process in kB, from `VmHWM` in `/proc/self/status`.
+pub fn peak_memory_kb() -> Option<u64> {
+    fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+pub fn render() -> String {
+    let mut out = String::new();
+    let mut last = "";
+    for counter in ALL.iter() {
+        if counter.name != last {
+            let _ = writeln!(out, "# HELP {} {}", counter.name, counter.help);
+            let _ = writeln!(out, "# TYPE {} counter", counter.name);
+            last = counter.name;
+        }
+        let _ = writeln!(
+            out,
+            "{}{{{}}} {}",
+            counter.name,
+            counter.labels,
+            counter.value.load(Ordering::Relaxed)
+        );
+    }
+    out
+}
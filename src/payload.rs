@@ -0,0 +1,276 @@
+//! Prepares the profiles sent to each sink according to its settings.
+use crate::settings::SinkSettings;
+use crate::signature;
+use cis_profile::schema::Profile;
+use failure::Error;
+use serde::Deserialize;
+use serde_json::Map;
+use serde_json::Value;
+
+/// Classification of an attribute as found in its metadata, from least to most restricted.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Classification {
+    #[serde(rename = "PUBLIC")]
+    Public,
+    #[serde(rename = "MOZILLA CONFIDENTIAL")]
+    MozillaConfidential,
+    #[serde(rename = "WORKGROUP CONFIDENTIAL: STAFF ONLY")]
+    StaffOnly,
+    #[serde(rename = "WORKGROUP CONFIDENTIAL")]
+    WorkgroupConfidential,
+    #[serde(rename = "INDIVIDUAL CONFIDENTIAL")]
+    IndividualConfidential,
+}
+
+/// Display level of an attribute as found in its metadata, from least to most restricted.
+/// `null` means the attribute is never displayed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayLevel {
+    Public,
+    Authenticated,
+    Vouched,
+    Ndaed,
+    Staff,
+    Private,
+    Null,
+}
+
+/// Serializes the profile once for all sinks, verifying its signatures if configured.
+pub fn serialize(profile: &Profile) -> Result<Value, Error> {
+    let mut value = serde_json::to_value(profile)?;
+    signature::check(&mut value)?;
+    Ok(value)
+}
+
+/// Turns a serialized profile into what the sink should receive.
+pub fn prepare(mut value: Value, settings: &SinkSettings) -> Value {
+    let redacted = redact(&mut value, settings);
+    if redacted > 0 {
+        debug!("redacted {} attributes", redacted);
+    }
+    transform(value, settings)
+}
+
+pub fn is_attribute(obj: &Map<String, Value>) -> bool {
+    obj.get("metadata").is_some_and(Value::is_object)
+        && (obj.contains_key("value") || obj.contains_key("values"))
+}
+
+/// Applies the field allowlist, flattening and renames, in that order.
+fn transform(value: Value, settings: &SinkSettings) -> Value {
+    let mut value = if settings.fields.is_empty() {
+        value
+    } else {
+        let mut selected = Value::Object(Map::new());
+        for field in &settings.fields {
+            if let Some(v) = value.pointer(&pointer(field)) {
+                insert(&mut selected, field, v.clone());
+            }
+        }
+        selected
+    };
+    if settings.flatten {
+        flatten(&mut value);
+    }
+    for rename in &settings.rename {
+        if let Some(v) = take(&mut value, &rename.from) {
+            insert(&mut value, &rename.to, v);
+        }
+    }
+    value
+}
+
+/// Replaces every `{ metadata, signature, value(s) }` attribute with its value(s).
+fn flatten(value: &mut Value) {
+    if let Value::Object(obj) = value {
+        if is_attribute(obj) {
+            *value = obj
+                .remove("value")
+                .or_else(|| obj.remove("values"))
+                .unwrap_or_default();
+        } else {
+            obj.values_mut().for_each(flatten);
+        }
+    }
+}
+
+/// Turns a dotted path like `staff_information.title` into a JSON pointer.
+fn pointer(path: &str) -> String {
+    path.split('.').map(|part| format!("/{}", part)).collect()
+}
+
+fn insert(target: &mut Value, path: &str, v: Value) {
+    let mut current = target;
+    let mut parts = path.split('.').peekable();
+    while let Some(part) = parts.next() {
+        let obj = match current {
+            Value::Object(obj) => obj,
+            _ => return,
+        };
+        if parts.peek().is_none() {
+            obj.insert(part.to_owned(), v);
+            return;
+        }
+        current = obj.entry(part).or_insert_with(|| Value::Object(Map::new()));
+    }
+}
+
+/// Removes the field at `path`, dropping the parents it leaves empty.
+fn take(value: &mut Value, path: &str) -> Option<Value> {
+    let obj = value.as_object_mut()?;
+    let (first, rest) = match path.split_once('.') {
+        Some(split) => split,
+        None => return obj.remove(path),
+    };
+    let child = obj.get_mut(first)?;
+    let v = take(child, rest)?;
+    if child.as_object().is_some_and(Map::is_empty) {
+        obj.remove(first);
+    }
+    Some(v)
+}
+
+/// Removes the values of all attributes above the sink's classification or display level,
+/// together with their signatures, whose JWS payloads carry the value as well. Attributes with
+/// unknown or missing metadata are removed as soon as a limit is set.
+fn redact(value: &mut Value, settings: &SinkSettings) -> usize {
+    if settings.max_classification.is_none() && settings.max_display.is_none() {
+        return 0;
+    }
+    let obj = match value {
+        Value::Object(obj) => obj,
+        _ => return 0,
+    };
+    if !is_attribute(obj) {
+        return obj.values_mut().map(|v| redact(v, settings)).sum();
+    }
+    let metadata = &obj["metadata"];
+    let classification = Classification::deserialize(&metadata["classification"]).ok();
+    let display = DisplayLevel::deserialize(&metadata["display"]).ok();
+    let allowed = settings
+        .max_classification
+        .is_none_or(|max| classification.is_some_and(|c| c <= max))
+        && settings
+            .max_display
+            .is_none_or(|max| display.is_some_and(|d| d <= max));
+    if allowed {
+        return 0;
+    }
+    for key in ["value", "values"] {
+        if let Some(v) = obj.get_mut(key) {
+            *v = Value::Null;
+        }
+    }
+    obj.remove("signature");
+    1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::settings::Rename;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use serde_json::json;
+
+    #[test]
+    fn test_redact_above_policy() {
+        let cost_center = json!({
+            "metadata": { "classification": "WORKGROUP CONFIDENTIAL: STAFF ONLY", "display": "staff" },
+            "value": "1337"
+        });
+        let jws = format!(
+            "eyJhbGciOiJSUzI1NiJ9.{}.c2ln",
+            URL_SAFE_NO_PAD.encode(cost_center.to_string())
+        );
+        let mut signed_cost_center = cost_center;
+        signed_cost_center["signature"] = json!({
+            "publisher": { "alg": "RS256", "typ": "JWS", "name": "hris", "value": jws },
+            "additional": [{ "alg": "RS256", "typ": "JWS", "name": "cis", "value": jws }]
+        });
+        let mut profile = json!({
+            "first_name": {
+                "metadata": { "classification": "PUBLIC", "display": "public" },
+                "value": "Hans"
+            },
+            "pronouns": {
+                "metadata": { "classification": "MOZILLA CONFIDENTIAL", "display": "staff" },
+                "value": "they/them"
+            },
+            "staff_information": {
+                "cost_center": signed_cost_center
+            },
+            "access_information": {
+                "ldap": {
+                    "metadata": { "classification": "INDIVIDUAL CONFIDENTIAL", "display": null },
+                    "values": { "team_moco": null }
+                }
+            }
+        });
+        let settings = SinkSettings {
+            max_classification: Some(Classification::MozillaConfidential),
+            max_display: Some(DisplayLevel::Staff),
+            ..Default::default()
+        };
+        assert_eq!(redact(&mut profile, &settings), 2);
+        assert_eq!(profile["pronouns"]["value"], "they/them");
+        assert_eq!(
+            profile["staff_information"]["cost_center"]["value"],
+            Value::Null
+        );
+        assert_eq!(profile["access_information"]["ldap"]["values"], Value::Null);
+        let sent = profile.to_string();
+        assert!(!sent.contains("1337"));
+        assert!(!sent.contains(&jws[..40]));
+        assert!(profile["staff_information"]["cost_center"]
+            .get("signature")
+            .is_none());
+    }
+
+    #[test]
+    fn test_transform_selects_flattens_and_renames() {
+        let profile = json!({
+            "user_id": { "metadata": { "display": "public" }, "value": "ad|Mozilla-LDAP|hknall" },
+            "first_name": { "metadata": { "display": "public" }, "value": "Hans" },
+            "staff_information": {
+                "title": { "metadata": { "display": "staff" }, "value": "Dino" },
+                "team": { "metadata": { "display": "staff" }, "value": "IAM" }
+            },
+            "identities": { "metadata": { "display": "staff" }, "values": { "github": "hknall" } }
+        });
+        let settings = SinkSettings {
+            fields: vec![
+                String::from("user_id"),
+                String::from("first_name"),
+                String::from("staff_information.title"),
+                String::from("identities"),
+            ],
+            rename: vec![
+                Rename {
+                    from: String::from("staff_information.title"),
+                    to: String::from("title"),
+                },
+                Rename {
+                    from: String::from("first_name"),
+                    to: String::from("name"),
+                },
+                Rename {
+                    from: String::from("name"),
+                    to: String::from("names.first"),
+                },
+            ],
+            flatten: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            transform(profile, &settings),
+            json!({
+                "user_id": "ad|Mozilla-LDAP|hknall",
+                "names": { "first": "Hans" },
+                "title": "Dino",
+                "identities": { "github": "hknall" }
+            })
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use crate::context::EventContext;
+use crate::notification::Notification;
+use crate::settings::AuditSettings;
+use crate::updater::Ack;
+use chrono::DateTime;
+use chrono::Utc;
+use failure::Error;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// A delete waiting for its grace period to pass.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingDelete {
+    pub notification: Notification,
+    pub correlation_id: String,
+    pub scheduled_at: DateTime<Utc>,
+    pub due_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub ctx: EventContext,
+}
+
+/// Deletes scheduled by the updater, keyed by user_id. A delete can hold the ack of the
+/// notification that scheduled it, e.g. to keep its SQS message in flight until the delete is
+/// done. Acks are not persisted, every other change is written to `path` if there is one.
+#[derive(Clone, Default)]
+pub struct PendingDeletes {
+    inner: Arc<Mutex<BTreeMap<String, Scheduled>>>,
+    path: Option<PathBuf>,
+}
+
+type Scheduled = (PendingDelete, Option<Ack>);
+
+impl PendingDeletes {
+    /// Reloads the deletes scheduled before lookout stopped, they keep their correlation id.
+    pub fn load(settings: &AuditSettings) -> Result<Self, Error> {
+        let path = PathBuf::from(&settings.pending_path);
+        let pending: Vec<PendingDelete> = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(file)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        if !pending.is_empty() {
+            info!("reloaded {} scheduled deletes", pending.len());
+        }
+        let inner = pending
+            .into_iter()
+            .map(|mut p| {
+                p.ctx = EventContext {
+                    correlation_id: p.correlation_id.clone(),
+                    ..Default::default()
+                };
+                (p.notification.id.clone(), (p, None))
+            })
+            .collect();
+        Ok(PendingDeletes {
+            inner: Arc::new(Mutex::new(inner)),
+            path: Some(path),
+        })
+    }
+
+    /// Called with the lock held, so writes happen in the order of the changes.
+    fn persist(&self, inner: &BTreeMap<String, Scheduled>) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        if let Err(e) = write_atomically(path, inner.values().map(|(p, _)| p).collect()) {
+            error!("unable to persist {} scheduled deletes: {}", inner.len(), e);
+        }
+    }
+
+    /// Schedules a delete, replacing an earlier one for the same user. The earlier
+    /// notification is acked as done, the new one carries the delete.
+    pub fn schedule(
+        &self,
+        notification: Notification,
+        ctx: EventContext,
+        due_at: DateTime<Utc>,
+        ack: Option<Ack>,
+    ) -> PendingDelete {
+        let pending = PendingDelete {
+            correlation_id: ctx.correlation_id.clone(),
+            notification,
+            scheduled_at: Utc::now(),
+            due_at,
+            ctx,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let replaced = inner.insert(pending.notification.id.clone(), (pending.clone(), ack));
+        self.persist(&inner);
+        if let Some((_, Some(ack))) = replaced {
+            let _ = ack.send(true);
+        }
+        pending
+    }
+
+    /// A cancelled delete was handled as far as its notification is concerned.
+    pub fn cancel(&self, user_id: &str) -> Option<PendingDelete> {
+        let mut inner = self.inner.lock().unwrap();
+        let (pending, ack) = inner.remove(user_id)?;
+        self.persist(&inner);
+        if let Some(ack) = ack {
+            let _ = ack.send(true);
+        }
+        Some(pending)
+    }
+
+    pub fn list(&self) -> Vec<PendingDelete> {
+        let mut pending: Vec<_> = self
+            .inner
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(p, _)| p.clone())
+            .collect();
+        pending.sort_by_key(|p| p.due_at);
+        pending
+    }
+
+    pub fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.inner
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(p, _)| p.due_at)
+            .min()
+    }
+
+    /// Removes and returns every delete whose grace period has passed, with its ack.
+    pub fn take_due(&self) -> Vec<Scheduled> {
+        let now = Utc::now();
+        let mut inner = self.inner.lock().unwrap();
+        let due: Vec<String> = inner
+            .values()
+            .filter(|(p, _)| p.due_at <= now)
+            .map(|(p, _)| p.notification.id.clone())
+            .collect();
+        let due = due.iter().filter_map(|id| inner.remove(id)).collect();
+        self.persist(&inner);
+        due
+    }
+}
+
+/// Replaces the file as a whole, a crash never leaves half of it behind.
+fn write_atomically(path: &Path, pending: Vec<&PendingDelete>) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(&pending)?)?;
+    file.sync_data()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::notification::Operation;
+    use chrono::Duration;
+    use futures::channel::oneshot;
+
+    fn delete(id: &str) -> Notification {
+        Notification {
+            operation: Operation::Delete,
+            id: id.to_owned(),
+            time: 1551963674.0,
+        }
+    }
+
+    #[test]
+    fn test_scheduled_deletes_are_reloaded() {
+        let settings = AuditSettings {
+            pending_path: std::env::temp_dir()
+                .join(format!("pending-{}.json", uuid::Uuid::new_v4()))
+                .to_string_lossy()
+                .into_owned(),
+            ..Default::default()
+        };
+        let pending = PendingDeletes::load(&settings).unwrap();
+        let due_at = Utc::now() + Duration::hours(1);
+        let (ack, mut acked) = oneshot::channel();
+        pending.schedule(delete("hknall"), EventContext::default(), due_at, Some(ack));
+        let ctx = EventContext::default();
+        pending.schedule(delete("hknall"), ctx.clone(), due_at, None);
+        assert_eq!(acked.try_recv(), Ok(Some(true)));
+        pending.schedule(delete("fiona"), EventContext::default(), due_at, None);
+        assert!(pending.cancel("fiona").is_some());
+
+        let reloaded = PendingDeletes::load(&settings).unwrap().list();
+        assert_eq!(reloaded.len(), 1);
+        assert_eq!(reloaded[0].notification.id, "hknall");
+        assert_eq!(reloaded[0].ctx.correlation_id, ctx.correlation_id);
+        assert_eq!(reloaded[0].due_at, due_at);
+        std::fs::remove_file(&settings.pending_path).unwrap();
+    }
+}
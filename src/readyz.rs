@@ -0,0 +1,158 @@
+use crate::cis;
+use crate::settings::DinoParkSettings;
+use crate::settings::ReadinessSettings;
+use crate::sink::CircuitState;
+use crate::sink::Sink;
+use crate::status::UpdaterStatus;
+use actix_web::dev::HttpServiceFactory;
+use actix_web::web;
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+use cis_client::settings::CisSettings;
+use failure::Error;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+pub struct Readiness {
+    status: UpdaterStatus,
+    settings: ReadinessSettings,
+    cis_settings: CisSettings,
+    groups_enabled: bool,
+    last_token: Mutex<Option<Instant>>,
+}
+
+impl Readiness {
+    pub fn new(
+        status: UpdaterStatus,
+        settings: ReadinessSettings,
+        cis_settings: CisSettings,
+        dino_park_settings: &DinoParkSettings,
+    ) -> Self {
+        Readiness {
+            status,
+            settings,
+            cis_settings,
+            groups_enabled: dino_park_settings.groups_update_endpoint.is_some(),
+            last_token: Mutex::new(None),
+        }
+    }
+
+    async fn check_token(&self) -> Result<(), Error> {
+        let interval = Duration::from_secs(self.settings.token_check_interval);
+        if let Some(last) = *self.last_token.lock().unwrap() {
+            if last.elapsed() < interval {
+                return Ok(());
+            }
+        }
+        fetch_cis_token(&self.cis_settings).await?;
+        *self.last_token.lock().unwrap() = Some(Instant::now());
+        Ok(())
+    }
+
+    fn sinks(&self) -> impl Iterator<Item = Sink> + '_ {
+        Sink::ALL
+            .iter()
+            .copied()
+            .filter(move |sink| *sink != Sink::Groups || self.groups_enabled)
+    }
+}
+
+/// Requests a token from the CIS token endpoint to check the configured credentials.
+pub async fn fetch_cis_token(cis_settings: &CisSettings) -> Result<(), Error> {
+    cis::fetch_token(&cis_settings.client_config)
+        .await
+        .map(drop)
+}
+
+/// Combines the individual checks into whether lookout is ready and the response body.
+fn report(
+    running: bool,
+    token: Result<(), Error>,
+    depth: usize,
+    max_depth: usize,
+    circuits: impl Iterator<Item = (Sink, CircuitState)>,
+) -> (bool, Value) {
+    let queue_ok = depth <= max_depth;
+    let mut sinks = Map::new();
+    let mut sinks_ok = true;
+    for (sink, circuit) in circuits {
+        sinks_ok &= circuit != CircuitState::Open;
+        sinks.insert(sink.to_string(), json!(circuit));
+    }
+    let ready = running && token.is_ok() && queue_ok && sinks_ok;
+    let body = json!({
+        "ready": ready,
+        "updater": { "ok": running },
+        "cis_token": {
+            "ok": token.is_ok(),
+            "error": token.err().map(|e| e.to_string()),
+        },
+        "queue": {
+            "ok": queue_ok,
+            "depth": depth,
+            "max_depth": max_depth,
+        },
+        "sinks": { "ok": sinks_ok, "circuits": sinks },
+    });
+    (ready, body)
+}
+
+async fn readyz(readiness: Data<Readiness>) -> HttpResponse {
+    let (ready, body) = report(
+        readiness.status.is_running(),
+        readiness.check_token().await,
+        readiness.status.queue_depth(),
+        readiness.settings.max_queue_depth,
+        readiness.sinks().map(|sink| (sink, sink.circuit())),
+    );
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+pub fn readyz_app(readiness: Data<Readiness>) -> impl HttpServiceFactory {
+    web::scope("/readyz")
+        .app_data(readiness)
+        .service(web::resource("").to(readyz))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn closed() -> impl Iterator<Item = (Sink, CircuitState)> {
+        vec![
+            (Sink::Orgchart, CircuitState::Closed),
+            (Sink::Search, CircuitState::Closed),
+        ]
+        .into_iter()
+    }
+
+    #[test]
+    fn test_ready_only_if_all_checks_pass() {
+        let (ready, body) = report(true, Ok(()), 3, 10, closed());
+        assert!(ready);
+        assert_eq!(body["sinks"]["circuits"]["search"], "closed");
+
+        assert!(!report(false, Ok(()), 3, 10, closed()).0);
+        assert!(!report(true, Err(failure::format_err!("no token")), 3, 10, closed()).0);
+        assert!(!report(true, Ok(()), 11, 10, closed()).0);
+
+        let open = closed().chain(Some((Sink::Groups, CircuitState::Open)));
+        let (ready, body) = report(true, Ok(()), 3, 10, open);
+        assert!(!ready);
+        assert_eq!(body["sinks"]["ok"], false);
+        assert_eq!(body["sinks"]["circuits"]["groups"], "open");
+
+        let half_open = closed().chain(Some((Sink::Pictures, CircuitState::HalfOpen)));
+        let (ready, body) = report(true, Ok(()), 3, 10, half_open);
+        assert!(ready);
+        assert_eq!(body["sinks"]["circuits"]["pictures"], "half_open");
+    }
+}
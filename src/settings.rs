@@ -1,3 +1,5 @@
+use crate::payload::Classification;
+use crate::payload::DisplayLevel;
 use cis_client::settings::CisSettings;
 use config::{Config, ConfigError, Environment, File};
 use dino_park_gate::settings::AuthValidationSettings;
@@ -16,6 +18,55 @@ pub struct DinoParkSettings {
     pub picture_delete_endpoint: String,
     pub groups_delete_endpoint: Option<String>,
     pub uuid_by_user_id_endpoint: String,
+    #[serde(default)]
+    pub sinks: SinksSettings,
+}
+
+/// How bulk updates send a page of profiles.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkEncoding {
+    /// `multipart/form-data` with a JSON array in a file named `data`.
+    #[default]
+    Multipart,
+    /// `application/x-ndjson`, one profile per line.
+    Ndjson,
+    /// `application/json` array.
+    Array,
+}
+
+/// Moves the field at the dotted path `from` to `to`.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+}
+
+/// What each sink receives.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct SinkSettings {
+    /// Attributes classified above this are sent without their values.
+    pub max_classification: Option<Classification>,
+    /// Attributes with a display level above this are sent without their values.
+    pub max_display: Option<DisplayLevel>,
+    /// Dotted paths of the fields to send, everything if empty.
+    pub fields: Vec<String>,
+    /// Replace attributes by their plain value(s), dropping metadata and signatures.
+    pub flatten: bool,
+    /// Moves fields from one dotted path to another, applied last and in order.
+    pub rename: Vec<Rename>,
+    /// Compress payloads with gzip, the sink must accept `Content-Encoding: gzip`.
+    pub gzip: bool,
+    pub bulk_encoding: BulkEncoding,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SinksSettings {
+    pub orgchart: SinkSettings,
+    pub search: SinkSettings,
+    pub groups: SinkSettings,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -24,11 +75,242 @@ pub struct AuthSettings {
     pub validation: AuthValidationSettings,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ReadinessSettings {
+    /// Report the pod as not ready once more messages than this are waiting.
+    pub max_queue_depth: usize,
+    /// Seconds a successful CIS token fetch is trusted before trying again.
+    pub token_check_interval: u64,
+}
+
+impl Default for ReadinessSettings {
+    fn default() -> Self {
+        ReadinessSettings {
+            max_queue_depth: 1000,
+            token_check_interval: 300,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DiagnosticsSettings {
+    /// Path probed on the origin of every configured DinoPark endpoint.
+    pub health_path: String,
+    /// Timeout in milliseconds for a single probe.
+    pub timeout_ms: u64,
+}
+
+impl Default for DiagnosticsSettings {
+    fn default() -> Self {
+        DiagnosticsSettings {
+            health_path: String::from("/healthz"),
+            timeout_ms: 5000,
+        }
+    }
+}
+
+/// What to do with notifications CIS sends with an unknown operation.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownOperationPolicy {
+    /// Treat it as a delete. CIS sends Unknown instead of Delete.
+    #[default]
+    Delete,
+    Ignore,
+    /// Fetch the profile and update it if it is still active, delete it otherwise.
+    Infer,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct BulkSettings {
+    /// Profiles sent to the sinks per request, 0 keeps the pages CIS returns.
+    pub page_size: usize,
+    /// Pages fetched from CIS ahead of the one uploading.
+    pub pages_in_flight: usize,
+    /// Bodies are cut once they reach this many bytes, the rest of the page is sent in the
+    /// next request. 0 sends every page in one request.
+    pub max_body_bytes: usize,
+}
+
+impl Default for BulkSettings {
+    fn default() -> Self {
+        BulkSettings {
+            page_size: 0,
+            pages_in_flight: 2,
+            max_body_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct UpdaterSettings {
+    /// Seconds a delete waits before it is executed. A later update or create for the same
+    /// user cancels it. 0 deletes immediately.
+    pub delete_grace_period: u64,
+    pub unknown_operation: UnknownOperationPolicy,
+    pub bulk: BulkSettings,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuditSettings {
+    /// File the audit trail of deletions is appended to, on a persistent volume in production.
+    pub path: String,
+    /// File scheduled deletes are kept in until they are executed or cancelled.
+    pub pending_path: String,
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        AuditSettings {
+            path: String::from("/var/lib/dino-park-lookout/audit.jsonl"),
+            pending_path: String::from("/var/lib/dino-park-lookout/pending.json"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TracingExporter {
+    None,
+    Stdout,
+    Otlp,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct TracingSettings {
+    pub exporter: TracingExporter,
+    /// Base URL of the OTLP/HTTP collector, `/v1/traces` is appended.
+    pub endpoint: String,
+    pub service_name: String,
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        TracingSettings {
+            exporter: TracingExporter::None,
+            endpoint: String::from("http://localhost:4318"),
+            service_name: String::from("dino-park-lookout"),
+        }
+    }
+}
+
+/// Where the certificates SNS messages are signed with come from.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "lowercase", tag = "source")]
+pub enum SnsCertificateSource {
+    /// Fetch `SigningCertURL` if it is an https URL to a `.pem` file on an SNS endpoint
+    /// (`sns.<region>.amazonaws.com`, or `.amazonaws.com.cn`).
+    Url,
+    /// Verify every message against a local PEM certificate, e.g. for a local stand-in.
+    File { path: String },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SnsSettings {
+    /// Serve `/events/sns` for SNS HTTP subscriptions.
+    pub enabled: bool,
+    /// Topics messages are accepted from.
+    pub topic_arns: Vec<String>,
+    pub certificate: SnsCertificateSource,
+}
+
+impl Default for SnsSettings {
+    fn default() -> Self {
+        SnsSettings {
+            enabled: false,
+            topic_arns: vec![],
+            certificate: SnsCertificateSource::Url,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SqsSettings {
+    /// Consume notifications from `queue_url` in addition to the web hooks.
+    pub enabled: bool,
+    pub queue_url: String,
+    /// Overrides the SQS endpoint, e.g. for a local SQS compatible stand-in.
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    /// Seconds to long-poll for messages.
+    pub wait_time: i32,
+    /// Seconds a received message stays invisible, extended every half of it until the
+    /// notification was processed.
+    pub visibility_timeout: i32,
+    /// Seconds until a message that failed to process becomes visible again. After the queue's
+    /// maxReceiveCount its redrive policy moves it to the dead-letter queue.
+    pub retry_delay: i32,
+    pub max_messages: i32,
+    /// Messages handled at once, including those kept in flight for a scheduled delete. No
+    /// messages are received while this many are in flight.
+    pub max_in_flight: usize,
+}
+
+impl Default for SqsSettings {
+    fn default() -> Self {
+        SqsSettings {
+            enabled: false,
+            queue_url: String::default(),
+            endpoint: None,
+            region: None,
+            wait_time: 20,
+            visibility_timeout: 300,
+            retry_delay: 60,
+            max_messages: 10,
+            max_in_flight: 100,
+        }
+    }
+}
+
+/// What to do with profiles carrying attributes whose publisher signature cannot be verified.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerificationPolicy {
+    /// Do not verify signatures.
+    #[default]
+    Off,
+    /// Send the profile without the values of those attributes.
+    Strip,
+    /// Do not send the profile at all.
+    Reject,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct VerificationSettings {
+    /// The publisher keys are taken from `cis.verify_keys`.
+    pub policy: VerificationPolicy,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub cis: CisSettings,
     pub dino_park: DinoParkSettings,
     pub auth: AuthSettings,
+    #[serde(default)]
+    pub readiness: ReadinessSettings,
+    #[serde(default)]
+    pub diagnostics: DiagnosticsSettings,
+    #[serde(default)]
+    pub tracing: TracingSettings,
+    #[serde(default)]
+    pub audit: AuditSettings,
+    #[serde(default)]
+    pub updater: UpdaterSettings,
+    #[serde(default)]
+    pub sns: SnsSettings,
+    #[serde(default)]
+    pub sqs: SqsSettings,
+    #[serde(default)]
+    pub verification: VerificationSettings,
 }
 
 impl Settings {
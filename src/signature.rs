@@ -0,0 +1,229 @@
+//! Verifies the publisher signatures of profile attributes before profiles are sent to the
+//! sinks. Every attribute CIS stores is signed by its publisher, the keys are the ones
+//! `cis.verify_keys` configures for cis_client's key store.
+use crate::error::UnverifiedProfile;
+use crate::metrics;
+use crate::payload::is_attribute;
+use crate::settings::VerificationPolicy;
+use crate::settings::VerificationSettings;
+use cis_client::secrets::get_store_from_settings;
+use cis_client::settings::CisSettings;
+use cis_profile::crypto::SecretStore;
+use cis_profile::crypto::Verifier;
+use cis_profile::schema::StandardAttributeBoolean;
+use cis_profile::schema::StandardAttributeString;
+use cis_profile::schema::StandardAttributeValues;
+use failure::format_err;
+use failure::Error;
+use serde_json::Map;
+use serde_json::Value;
+use std::sync::OnceLock;
+
+static VERIFIER: OnceLock<ProfileVerifier> = OnceLock::new();
+
+pub struct ProfileVerifier {
+    policy: VerificationPolicy,
+    store: SecretStore,
+}
+
+impl ProfileVerifier {
+    pub fn new(policy: VerificationPolicy, store: SecretStore) -> Self {
+        ProfileVerifier { policy, store }
+    }
+
+    /// Checks all attributes of the serialized profile and applies the policy to the ones that
+    /// cannot be verified.
+    pub fn check(&self, profile: &mut Value) -> Result<(), Error> {
+        let strip = self.policy == VerificationPolicy::Strip;
+        let mut unverified = vec![];
+        self.check_attributes(profile, "", strip, &mut unverified);
+        if unverified.is_empty() {
+            metrics::PROFILES_VERIFIED.inc();
+            return Ok(());
+        }
+        let user_id = profile["user_id"]["value"]
+            .as_str()
+            .unwrap_or("unknown")
+            .to_owned();
+        let attributes = unverified.join(", ");
+        if strip {
+            metrics::PROFILES_STRIPPED.inc();
+            warn!(
+                "stripped unverifiable attributes for {}: {}",
+                user_id, attributes
+            );
+            Ok(())
+        } else {
+            metrics::PROFILES_REJECTED.inc();
+            warn!(
+                "rejecting {}: unverifiable attributes: {}",
+                user_id, attributes
+            );
+            Err(UnverifiedProfile {
+                user_id,
+                attributes,
+            }
+            .into())
+        }
+    }
+
+    fn check_attributes(&self, value: &mut Value, path: &str, strip: bool, out: &mut Vec<String>) {
+        let obj = match value {
+            Value::Object(obj) => obj,
+            _ => return,
+        };
+        if !is_attribute(obj) {
+            for (key, v) in obj.iter_mut() {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                self.check_attributes(v, &path, strip, out);
+            }
+            return;
+        }
+        if let Err(e) = self.verify_attribute(obj) {
+            out.push(format!("{} ({})", path, e));
+            if strip {
+                for key in ["value", "values"] {
+                    if let Some(v) = obj.get_mut(key) {
+                        *v = Value::Null;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Attributes without a value carry nothing worth verifying. The others are verified as the
+    /// attribute type they were signed as.
+    fn verify_attribute(&self, obj: &Map<String, Value>) -> Result<(), Error> {
+        let data = obj.get("value").or_else(|| obj.get("values"));
+        if data.is_none_or(is_unset) {
+            return Ok(());
+        }
+        let attribute = Value::Object(obj.clone());
+        let res = match data {
+            _ if obj.contains_key("values") => {
+                self.store
+                    .verify_attribute(&serde_json::from_value::<StandardAttributeValues>(
+                        attribute,
+                    )?)
+            }
+            Some(Value::Bool(_)) => self.store.verify_attribute(&serde_json::from_value::<
+                StandardAttributeBoolean,
+            >(attribute)?),
+            _ => self
+                .store
+                .verify_attribute(&serde_json::from_value::<StandardAttributeString>(
+                    attribute,
+                )?),
+        };
+        res.map_err(|e| format_err!("{}", e))
+    }
+}
+
+fn is_unset(v: &Value) -> bool {
+    match v {
+        Value::Null => true,
+        Value::String(s) => s.is_empty(),
+        Value::Object(obj) => obj.is_empty(),
+        _ => false,
+    }
+}
+
+/// Loads the publisher keys from `cis.verify_keys` unless verification is off.
+pub async fn init(settings: &VerificationSettings, cis: &CisSettings) -> Result<(), Error> {
+    if settings.policy != VerificationPolicy::Off {
+        let store = get_store_from_settings(cis)
+            .await
+            .map_err(|e| format_err!("unable to load verify keys: {}", e))?;
+        info!("verifying profile signatures ({:?})", settings.policy);
+        let _ = VERIFIER.set(ProfileVerifier::new(settings.policy, store));
+    }
+    Ok(())
+}
+
+/// Applies the verification policy to a serialized profile, a no-op if verification is off.
+pub fn check(profile: &mut Value) -> Result<(), Error> {
+    match VERIFIER.get() {
+        Some(verifier) => verifier.check(profile),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cis_profile::crypto::Signer;
+    use cis_profile::schema::PublisherAuthority;
+    use serde_json::json;
+
+    const KEY: &str = include_str!("../tests/data/publisher-test-key.pem");
+    const PUB: &str = include_str!("../tests/data/publisher-test-pub.pem");
+
+    /// Signs for ldap and mozilliansorg but only verifies ldap.
+    fn store() -> SecretStore {
+        SecretStore::default()
+            .with_sign_keys_from_inline_iter(vec![
+                (String::from("ldap"), String::from(KEY)),
+                (String::from("mozilliansorg"), String::from(KEY)),
+            ])
+            .unwrap()
+            .with_verify_keys_from_inline_iter(vec![(String::from("ldap"), String::from(PUB))])
+            .unwrap()
+    }
+
+    fn attribute(value: &str, signed_value: &str, publisher: PublisherAuthority) -> Value {
+        let mut attribute = StandardAttributeString {
+            value: Some(signed_value.to_owned()),
+            ..Default::default()
+        };
+        attribute.signature.publisher.name = publisher;
+        store().sign_attribute(&mut attribute).unwrap();
+        let mut attribute = serde_json::to_value(attribute).unwrap();
+        attribute["value"] = json!(value);
+        attribute
+    }
+
+    #[test]
+    fn test_strip_or_reject_unverifiable_attributes() {
+        let user_id = "ad|Mozilla-LDAP|hknall";
+        let mut primary_email = attribute(
+            "hknall@mozilla.com",
+            "hknall@mozilla.com",
+            PublisherAuthority::Ldap,
+        );
+        primary_email["metadata"]["verified"] = json!(true);
+        let profile = json!({
+            "user_id": attribute(user_id, user_id, PublisherAuthority::Ldap),
+            "first_name": attribute("Hans", "Hans", PublisherAuthority::Ldap),
+            "staff_information": {
+                "title": attribute("Chief Dino", "Dino", PublisherAuthority::Ldap)
+            },
+            "pronouns": attribute("they/them", "they/them", PublisherAuthority::Mozilliansorg),
+            "primary_email": primary_email,
+            "last_name": { "metadata": {}, "signature": {}, "value": null }
+        });
+
+        let mut stripped = profile.clone();
+        assert!(ProfileVerifier::new(VerificationPolicy::Strip, store())
+            .check(&mut stripped)
+            .is_ok());
+        assert_eq!(stripped["first_name"]["value"], "Hans");
+        assert_eq!(stripped["staff_information"]["title"]["value"], Value::Null);
+        assert_eq!(stripped["pronouns"]["value"], Value::Null);
+        assert_eq!(stripped["primary_email"]["value"], Value::Null);
+
+        let mut rejected = profile;
+        let err = ProfileVerifier::new(VerificationPolicy::Reject, store())
+            .check(&mut rejected)
+            .unwrap_err();
+        let err = err.downcast_ref::<UnverifiedProfile>().unwrap();
+        assert_eq!(err.user_id, user_id);
+        assert!(!err.attributes.contains("first_name"));
+        for attribute in ["staff_information.title", "pronouns", "primary_email"] {
+            assert!(err.attributes.contains(attribute), "{}", attribute);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use reqwest::Response;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Number of consecutive failures after which a sink's circuit is reported as open.
+const OPEN_AFTER_FAILURES: usize = 5;
+/// Seconds after the last failure until an open circuit is reported as half-open. Nothing reaches
+/// an unready pod, so without this a circuit could never close again.
+const OPEN_FOR_SECS: u64 = 30;
+
+static CONSECUTIVE_FAILURES: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Seconds since the epoch of each sink's last failure.
+static LAST_FAILURE: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Sink {
+    Orgchart,
+    Search,
+    Groups,
+    Pictures,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    /// Open but cooled down, the next request decides whether it closes or opens again.
+    HalfOpen,
+}
+
+impl Sink {
+    pub const ALL: [Sink; 4] = [Sink::Orgchart, Sink::Search, Sink::Groups, Sink::Pictures];
+
+    fn index(self) -> usize {
+        match self {
+            Sink::Orgchart => 0,
+            Sink::Search => 1,
+            Sink::Groups => 2,
+            Sink::Pictures => 3,
+        }
+    }
+
+    /// Records the outcome of a request to this sink, error responses count as failures.
+    /// Requests are never short-circuited, the state is only used to report the sink's health.
+    pub fn record<E>(self, res: &Result<Response, E>) {
+        self.record_ok(matches!(res, Ok(r) if r.status().is_success()));
+    }
+
+    fn record_ok(self, ok: bool) {
+        self.record_ok_at(ok, now());
+    }
+
+    fn record_ok_at(self, ok: bool, now: u64) {
+        let failures = &CONSECUTIVE_FAILURES[self.index()];
+        if ok {
+            failures.store(0, Ordering::Relaxed);
+        } else {
+            LAST_FAILURE[self.index()].store(now, Ordering::Relaxed);
+            failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn circuit(self) -> CircuitState {
+        self.circuit_at(now())
+    }
+
+    fn circuit_at(self, now: u64) -> CircuitState {
+        if CONSECUTIVE_FAILURES[self.index()].load(Ordering::Relaxed) < OPEN_AFTER_FAILURES {
+            CircuitState::Closed
+        } else if now < LAST_FAILURE[self.index()].load(Ordering::Relaxed) + OPEN_FOR_SECS {
+            CircuitState::Open
+        } else {
+            CircuitState::HalfOpen
+        }
+    }
+}
+
+/// Result of a single request to a sink.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Outcome {
+    pub ok: bool,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+pub type Outcomes = BTreeMap<Sink, Outcome>;
+
+impl<E: fmt::Display> From<Result<Response, E>> for Outcome {
+    fn from(res: Result<Response, E>) -> Self {
+        match res {
+            Ok(r) => Outcome {
+                ok: r.status().is_success(),
+                status: Some(r.status().as_u16()),
+                error: None,
+            },
+            Err(e) => Outcome {
+                ok: false,
+                status: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Sink::Orgchart => "orgchart",
+            Sink::Search => "search",
+            Sink::Groups => "groups",
+            Sink::Pictures => "pictures",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Circuits are global and updater tests send to every sink but groups, so one test covers
+    // the groups circuit from closed over open to half-open.
+    #[test]
+    fn test_circuit_opens_after_consecutive_failures() {
+        for _ in 0..OPEN_AFTER_FAILURES {
+            assert_eq!(Sink::Groups.circuit(), CircuitState::Closed);
+            Sink::Groups.record_ok(false);
+        }
+        assert_eq!(Sink::Groups.circuit(), CircuitState::Open);
+        Sink::Groups.record_ok(true);
+        assert_eq!(Sink::Groups.circuit(), CircuitState::Closed);
+
+        for _ in 0..OPEN_AFTER_FAILURES {
+            Sink::Groups.record_ok_at(false, 1000);
+        }
+        assert_eq!(Sink::Groups.circuit_at(1010), CircuitState::Open);
+        assert_eq!(
+            Sink::Groups.circuit_at(1000 + OPEN_FOR_SECS),
+            CircuitState::HalfOpen
+        );
+        Sink::Groups.record_ok_at(false, 1000 + OPEN_FOR_SECS);
+        assert_eq!(
+            Sink::Groups.circuit_at(1000 + OPEN_FOR_SECS),
+            CircuitState::Open
+        );
+        Sink::Groups.record_ok_at(true, 1000 + OPEN_FOR_SECS);
+        assert_eq!(
+            Sink::Groups.circuit_at(1000 + OPEN_FOR_SECS),
+            CircuitState::Closed
+        );
+    }
+}
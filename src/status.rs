@@ -0,0 +1,188 @@
+use chrono::DateTime;
+use chrono::Utc;
+use failure::Error;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Number of completed items kept for introspection.
+const HISTORY_SIZE: usize = 100;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct QueueItem {
+    pub kind: &'static str,
+    pub id: Option<String>,
+    pub enqueued_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Processing {
+    pub item: QueueItem,
+    pub started_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Completed {
+    pub item: QueueItem,
+    pub worker: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueueSnapshot {
+    pub depth: usize,
+    pub oldest_age_secs: Option<i64>,
+    pub waiting: Vec<QueueItem>,
+    pub workers: BTreeMap<String, Processing>,
+    pub completed: Vec<Completed>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    waiting: VecDeque<QueueItem>,
+    workers: BTreeMap<String, Processing>,
+    completed: VecDeque<Completed>,
+}
+
+#[derive(Default)]
+struct StatusInner {
+    running: AtomicBool,
+    queue: Mutex<QueueState>,
+}
+
+/// Shared view on the updater loop used by the readiness check and queue introspection.
+#[derive(Clone, Default)]
+pub struct UpdaterStatus {
+    inner: Arc<StatusInner>,
+}
+
+/// Marks the updater loop as running until dropped, so a panicking loop is reported as well.
+pub struct RunningGuard {
+    status: UpdaterStatus,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.status.inner.running.store(false, Ordering::SeqCst);
+    }
+}
+
+impl UpdaterStatus {
+    pub fn running(&self) -> RunningGuard {
+        self.inner.running.store(true, Ordering::SeqCst);
+        RunningGuard {
+            status: self.clone(),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.inner.running.load(Ordering::SeqCst)
+    }
+
+    /// Sends a message and records it as waiting in one step, so the waiting items are always
+    /// in the channel's order.
+    pub fn enqueue<T, E>(
+        &self,
+        item: QueueItem,
+        send: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut queue = self.inner.queue.lock().unwrap();
+        let res = send();
+        if res.is_ok() {
+            queue.waiting.push_back(item);
+        }
+        res
+    }
+
+    /// Removes the oldest waiting item, which is the message just received from the channel.
+    pub fn dequeued(&self) -> Option<QueueItem> {
+        self.inner.queue.lock().unwrap().waiting.pop_front()
+    }
+
+    pub fn started(&self, worker: &str, item: QueueItem) {
+        self.inner.queue.lock().unwrap().workers.insert(
+            worker.to_owned(),
+            Processing {
+                item,
+                started_at: Utc::now(),
+            },
+        );
+    }
+
+    pub fn finished<T>(&self, worker: &str, res: &Result<T, Error>) {
+        let mut queue = self.inner.queue.lock().unwrap();
+        if let Some(processing) = queue.workers.remove(worker) {
+            if queue.completed.len() >= HISTORY_SIZE {
+                queue.completed.pop_front();
+            }
+            queue.completed.push_back(Completed {
+                item: processing.item,
+                worker: worker.to_owned(),
+                started_at: processing.started_at,
+                finished_at: Utc::now(),
+                ok: res.is_ok(),
+                error: res.as_ref().err().map(|e| e.to_string()),
+            });
+        }
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.inner.queue.lock().unwrap().waiting.len()
+    }
+
+    pub fn snapshot(&self, limit: usize) -> QueueSnapshot {
+        let queue = self.inner.queue.lock().unwrap();
+        QueueSnapshot {
+            depth: queue.waiting.len(),
+            oldest_age_secs: queue
+                .waiting
+                .front()
+                .map(|item| (Utc::now() - item.enqueued_at).num_seconds()),
+            waiting: queue.waiting.iter().take(limit).cloned().collect(),
+            workers: queue.workers.clone(),
+            completed: queue.completed.iter().rev().take(limit).cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[test]
+    fn test_waiting_items_follow_channel_order() {
+        let status = UpdaterStatus::default();
+        let (sender, receiver) = channel();
+        let senders = (0..8)
+            .map(|t| {
+                let status = status.clone();
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let id = format!("{}-{}", t, i);
+                        let item = QueueItem {
+                            kind: "notification",
+                            id: Some(id.clone()),
+                            enqueued_at: Utc::now(),
+                        };
+                        status.enqueue(item, || sender.send(id)).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..800 {
+            let id = receiver.recv().unwrap();
+            assert_eq!(status.dequeued().unwrap().id, Some(id));
+        }
+        senders.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(status.queue_depth(), 0);
+    }
+}
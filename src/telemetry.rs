@@ -0,0 +1,222 @@
+use crate::settings::TracingExporter;
+use crate::settings::TracingSettings;
+use actix_web::http::header::HeaderMap as ActixHeaderMap;
+use failure::Error;
+use futures::future::BoxFuture;
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::trace::Span;
+use opentelemetry::trace::SpanBuilder;
+use opentelemetry::trace::Status;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::trace::Tracer;
+use opentelemetry::Context;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::export::trace::ExportResult;
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::export::trace::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::Resource;
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderName;
+use reqwest::header::HeaderValue;
+use reqwest::RequestBuilder;
+use reqwest::Response;
+use serde_json::json;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+const TRACER: &str = "dino-park-lookout";
+
+/// Installs the W3C trace context propagator and the configured exporter.
+pub fn init(settings: &TracingSettings) -> Result<(), Error> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let resource = Resource::new(vec![KeyValue::new(
+        "service.name",
+        settings.service_name.clone(),
+    )]);
+    let config = opentelemetry_sdk::trace::config().with_resource(resource);
+    match settings.exporter {
+        TracingExporter::None => {}
+        TracingExporter::Stdout => {
+            let provider = TracerProvider::builder()
+                .with_config(config)
+                .with_simple_exporter(StdoutExporter)
+                .build();
+            global::set_tracer_provider(provider);
+        }
+        TracingExporter::Otlp => {
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .http()
+                        .with_endpoint(&settings.endpoint),
+                )
+                .with_trace_config(config)
+                .install_batch(opentelemetry_sdk::runtime::TokioCurrentThread)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Continues the trace of an incoming request, or starts a new one.
+pub fn extract(headers: &ActixHeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&ActixExtractor(headers)))
+}
+
+/// Starts a span as child of `parent`.
+pub fn start(name: &'static str, parent: &Context) -> Context {
+    let span = global::tracer(TRACER).start_with_context(name, parent);
+    parent.with_span(span)
+}
+
+/// Records a span that started at `start` and ends now, e.g. the time a message spent queued.
+pub fn record_since(name: &'static str, start: SystemTime, parent: &Context) {
+    global::tracer(TRACER)
+        .build_with_context(SpanBuilder::from_name(name).with_start_time(start), parent)
+        .end();
+}
+
+/// Runs `f` in a span that is a child of the current context.
+pub async fn in_span<T, E: Display, F: Future<Output = Result<T, E>>>(
+    name: &'static str,
+    f: F,
+) -> Result<T, E> {
+    let cx = start(name, &Context::current());
+    let res = f.with_context(cx.clone()).await;
+    if let Err(ref e) = res {
+        cx.span().set_status(Status::error(e.to_string()));
+    }
+    cx.span().end();
+    res
+}
+
+pub trait SendTraced {
+    /// Sends the request in its own span and passes the trace context on to the receiver.
+    fn send_traced(self, name: &'static str) -> BoxFuture<'static, reqwest::Result<Response>>;
+}
+
+impl SendTraced for RequestBuilder {
+    fn send_traced(self, name: &'static str) -> BoxFuture<'static, reqwest::Result<Response>> {
+        let cx = start(name, &Context::current());
+        let mut headers = HeaderMap::new();
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&cx, &mut ReqwestInjector(&mut headers))
+        });
+        let send = self.headers(headers).send();
+        Box::pin(async move {
+            let res = send.with_context(cx.clone()).await;
+            let span = cx.span();
+            match res {
+                Ok(ref r) => {
+                    span.set_attribute(KeyValue::new(
+                        "http.status_code",
+                        i64::from(r.status().as_u16()),
+                    ));
+                    if r.status().is_server_error() {
+                        span.set_status(Status::error(r.status().to_string()));
+                    }
+                }
+                Err(ref e) => span.set_status(Status::error(e.to_string())),
+            }
+            span.end();
+            res
+        })
+    }
+}
+
+struct ActixExtractor<'a>(&'a ActixHeaderMap);
+
+impl Extractor for ActixExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct ReqwestInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for ReqwestInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Writes finished spans as JSON lines to stdout, meant for local development and tests.
+#[derive(Debug)]
+pub struct StdoutExporter;
+
+impl SpanExporter for StdoutExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        for span in batch {
+            let attributes: serde_json::Map<_, _> = span
+                .attributes
+                .iter()
+                .map(|kv| (kv.key.to_string(), json!(kv.value.to_string())))
+                .collect();
+            println!(
+                "{}",
+                json!({
+                    "name": span.name,
+                    "trace_id": span.span_context.trace_id().to_string(),
+                    "span_id": span.span_context.span_id().to_string(),
+                    "parent_span_id": span.parent_span_id.to_string(),
+                    "start_us": micros(span.start_time),
+                    "end_us": micros(span.end_time),
+                    "status": format!("{:?}", span.status),
+                    "attributes": attributes,
+                })
+            );
+        }
+        Box::pin(futures::future::ready(Ok(())))
+    }
+}
+
+fn micros(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_propagates_incoming_trace() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let req = TestRequest::default()
+            .insert_header((
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            ))
+            .to_http_request();
+        let cx = extract(req.headers());
+        let mut headers = HeaderMap::new();
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&cx, &mut ReqwestInjector(&mut headers))
+        });
+        let traceparent = headers.get("traceparent").unwrap().to_str().unwrap();
+        assert!(traceparent.starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+    }
+}
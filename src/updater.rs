@@ -1,43 +1,106 @@
+use crate::audit::AuditEntry;
+use crate::audit::AuditLog;
+use crate::body;
+use crate::body::Body;
+use crate::body::WithBody;
 use crate::bulk::Bulk;
+use crate::cis::PersonApi;
+use crate::context::EventContext;
+use crate::context::WithCorrelationId;
+use crate::error::ProfileNotFound;
+use crate::error::UnconfirmedDelete;
 use crate::error::UpdateError;
+use crate::lookup::Lookup;
+use crate::metrics;
 use crate::notification::Notification;
 use crate::notification::Operation;
+use crate::payload;
+use crate::pending::PendingDelete;
+use crate::pending::PendingDeletes;
+use crate::settings::BulkSettings;
 use crate::settings::DinoParkSettings;
+use crate::settings::UnknownOperationPolicy;
+use crate::settings::UpdaterSettings;
+use crate::sink::Outcome;
+use crate::sink::Outcomes;
+use crate::sink::Sink;
+use crate::status::QueueItem;
+use crate::status::UpdaterStatus;
+use crate::telemetry;
+use crate::telemetry::in_span;
+use crate::telemetry::SendTraced;
+use chrono::Duration;
+use chrono::Utc;
 use cis_client::getby::GetBy;
 use cis_client::sync::client::CisClientTrait;
-use cis_client::AsyncCisClientTrait;
 use cis_profile::schema::Profile;
+use failure::format_err;
 use failure::Error;
+use futures::channel::oneshot;
 use futures::future::join;
 use futures::future::join3;
 use futures::future::join4;
+use futures::future::join_all;
 use futures::FutureExt;
 use futures::TryFutureExt;
-use reqwest::multipart;
 use reqwest::Client;
 use serde::Deserialize;
 use serde_json::json;
 use serde_json::Value;
+use std::collections::BTreeMap;
+use std::mem;
 use std::sync::mpsc::channel;
+use std::sync::mpsc::sync_channel;
 use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::SendError;
 use std::sync::mpsc::Sender;
+use std::sync::mpsc::SyncSender;
+use std::thread;
 use std::thread::spawn;
+use std::time::SystemTime;
 use tokio::runtime::Runtime;
 
-#[derive(Clone, Debug)]
+/// Resolves to whether a notification was processed successfully.
+pub type Ack = oneshot::Sender<bool>;
+
+#[derive(Debug)]
 pub enum UpdateMessage {
-    Notification(Notification),
+    Notification(Notification, EventContext, Option<Ack>),
     Bulk(Bulk),
     Stop,
 }
 
+impl UpdateMessage {
+    fn queue_item(&self) -> QueueItem {
+        let (kind, id) = match self {
+            UpdateMessage::Notification(n, _, _) => ("notification", Some(n.id.clone())),
+            UpdateMessage::Bulk(_) => ("bulk", None),
+            UpdateMessage::Stop => ("stop", None),
+        };
+        QueueItem {
+            kind,
+            id,
+            enqueued_at: Utc::now(),
+        }
+    }
+}
+
+const UPDATER_WORKER: &str = "updater";
+
 #[derive(Deserialize)]
 struct UuidByUserId {
     uuid: Option<String>,
 }
 
 pub trait UpdaterClient {
-    fn update(&self, notification: Notification);
+    fn update(&self, notification: Notification, ctx: EventContext);
+    /// Like `update`, the returned receiver resolves once the notification was processed.
+    fn update_acked(
+        &self,
+        notification: Notification,
+        ctx: EventContext,
+    ) -> oneshot::Receiver<bool>;
     fn update_all(&self, bulk: Bulk);
     fn stop(&self);
 }
@@ -49,79 +112,157 @@ pub trait Updater<U: UpdaterClient> {
 #[derive(Clone)]
 pub struct InternalUpdaterClient {
     sender: Sender<UpdateMessage>,
+    status: UpdaterStatus,
+}
+
+impl InternalUpdaterClient {
+    fn send(&self, msg: UpdateMessage) -> Result<(), SendError<UpdateMessage>> {
+        self.status
+            .enqueue(msg.queue_item(), || self.sender.send(msg))
+    }
 }
 
 impl UpdaterClient for InternalUpdaterClient {
-    fn update(&self, notification: Notification) {
-        if let Err(e) = self.sender.send(UpdateMessage::Notification(notification)) {
+    fn update(&self, notification: Notification, ctx: EventContext) {
+        if let Err(e) = self.send(UpdateMessage::Notification(notification, ctx, None)) {
+            warn!("unable to send internally send notification: {}", e);
+        }
+    }
+    fn update_acked(
+        &self,
+        notification: Notification,
+        ctx: EventContext,
+    ) -> oneshot::Receiver<bool> {
+        let (ack, acked) = oneshot::channel();
+        if let Err(e) = self.send(UpdateMessage::Notification(notification, ctx, Some(ack))) {
             warn!("unable to send internally send notification: {}", e);
         }
+        acked
     }
     fn update_all(&self, bulk: Bulk) {
-        if let Err(e) = self.sender.send(UpdateMessage::Bulk(bulk)) {
+        if let Err(e) = self.send(UpdateMessage::Bulk(bulk)) {
             warn!("unable to send internally send notification: {}", e);
         }
     }
     fn stop(&self) {
-        if let Err(e) = self.sender.send(UpdateMessage::Stop) {
+        if let Err(e) = self.send(UpdateMessage::Stop) {
             warn!("unable to send internally send stop message: {}", e);
         }
     }
 }
 
-pub struct InternalUpdater<T: AsyncCisClientTrait + CisClientTrait> {
+pub struct InternalUpdater<T> {
     cis_client: T,
+    person_api: PersonApi,
     dino_park_settings: DinoParkSettings,
+    settings: UpdaterSettings,
     sender: Sender<UpdateMessage>,
     receiver: Receiver<UpdateMessage>,
+    status: UpdaterStatus,
+    audit: AuditLog,
+    pending: PendingDeletes,
 }
 
-impl<T: AsyncCisClientTrait + CisClientTrait + Clone + Sync + Send + 'static> InternalUpdater<T> {
-    pub fn new(cis_client: T, dino_park_settings: DinoParkSettings) -> Self {
+impl<T> InternalUpdater<T> {
+    pub fn new(
+        cis_client: T,
+        person_api: PersonApi,
+        dino_park_settings: DinoParkSettings,
+        settings: UpdaterSettings,
+        audit: AuditLog,
+        pending: PendingDeletes,
+    ) -> Self {
         let (sender, receiver) = channel();
         InternalUpdater {
             cis_client,
+            person_api,
             dino_park_settings,
+            settings,
             sender,
             receiver,
+            status: UpdaterStatus::default(),
+            audit,
+            pending,
+        }
+    }
+
+    pub fn status(&self) -> UpdaterStatus {
+        self.status.clone()
+    }
+
+    pub fn pending_deletes(&self) -> PendingDeletes {
+        self.pending.clone()
+    }
+
+    /// Waits for the next message, timing out when the next scheduled delete is due.
+    fn next_message(&self) -> Result<UpdateMessage, RecvTimeoutError> {
+        match self.pending.next_due() {
+            Some(due_at) => self
+                .receiver
+                .recv_timeout((due_at - Utc::now()).to_std().unwrap_or_default()),
+            None => self
+                .receiver
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
         }
     }
+}
 
+impl<T: CisClientTrait + Clone + Sync + Send + 'static> InternalUpdater<T> {
     pub fn run(&self) -> Result<(), Error> {
+        let _running = self.status.running();
         let rt = Runtime::new()?;
-        for msg in &self.receiver {
+        let mut bulk_runs = 0;
+        loop {
+            for (pending, ack) in self.pending.take_due() {
+                self.run_scheduled_delete(&rt, pending, ack);
+            }
+            let msg = match self.next_message() {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let item = self.status.dequeued().unwrap_or_else(|| msg.queue_item());
             debug!("got message: {:?}", msg);
             if let UpdateMessage::Stop = msg {
                 break;
             }
             match msg {
-                // Due to CIS sending Unknown instead of Delete we treat Unknown as Delete for now.
-                UpdateMessage::Notification(n)
-                    if n.operation == Operation::Delete || n.operation == Operation::Unknown =>
-                {
-                    let dino_park_settings = self.dino_park_settings.clone();
-                    info!("processing");
-                    if let Err(e) = rt.block_on(delete(&dino_park_settings, &n)) {
-                        warn!("unable to delete profile for {}: {}", &n.id, e);
-                    };
-                }
-                // UpdateMessage::Notification(n) if n.operation == Operation::Unknown => {
-                //     warn!("received unknown operation");
-                // }
-                UpdateMessage::Notification(n) => {
-                    let cis_client = self.cis_client.clone();
-                    let dino_park_settings = self.dino_park_settings.clone();
-                    info!("processing");
-                    if let Err(e) = rt.block_on(update(&cis_client, &dino_park_settings, &n)) {
-                        warn!("unable to update profile for {}: {}", &n.id, e);
-                    };
+                UpdateMessage::Notification(n, ctx, mut ack) => {
+                    telemetry::record_since(
+                        "queue_wait",
+                        SystemTime::from(item.enqueued_at),
+                        &ctx.trace,
+                    );
+                    self.status.started(UPDATER_WORKER, item);
+                    let res = rt.block_on(ctx.scope(self.handle(&n, &ctx, &mut ack)));
+                    self.status.finished(UPDATER_WORKER, &res);
+                    if let Some(ack) = ack {
+                        let _ = ack.send(res.is_ok());
+                    }
                 }
                 UpdateMessage::Bulk(_) => {
                     let cis_client = self.cis_client.clone();
                     let dino_park_settings = self.dino_park_settings.clone();
+                    let bulk_settings = self.settings.bulk.clone();
+                    let status = self.status.clone();
+                    bulk_runs += 1;
+                    let worker = format!("bulk-{}", bulk_runs);
                     spawn(move || {
                         debug!("processing");
-                        if let Err(e) = update_batch(&cis_client, &dino_park_settings) {
+                        status.started(&worker, item);
+                        let res = Runtime::new().map_err(Error::from).and_then(|rt| {
+                            update_batch(
+                                &rt,
+                                &cis_client,
+                                &dino_park_settings,
+                                &bulk_settings,
+                                None,
+                            )
+                        });
+                        let res = res.and_then(BulkUpdate::into_result);
+                        status.finished(&worker, &res);
+                        if let Err(e) = res {
                             warn!("unable to bulk update profiles for: {}", e);
                         };
                     });
@@ -129,209 +270,1042 @@ impl<T: AsyncCisClientTrait + CisClientTrait + Clone + Sync + Send + 'static> In
                 _ => {}
             };
         }
+        let kept = self.pending.list().len();
+        if kept > 0 {
+            info!("keeping {} scheduled deletes until restart", kept);
+        }
         info!("stop processing msgs");
         Ok(())
     }
 }
 
-impl<T: AsyncCisClientTrait + CisClientTrait> Updater<InternalUpdaterClient>
-    for InternalUpdater<T>
-{
+impl<T> InternalUpdater<T> {
+    /// Acks the notification that scheduled the delete once it is done. Deletes that could not
+    /// be confirmed with CIS are scheduled again, keeping the ack.
+    fn run_scheduled_delete(&self, rt: &Runtime, pending: PendingDelete, ack: Option<Ack>) {
+        let item = QueueItem {
+            kind: "scheduled_delete",
+            id: Some(pending.notification.id.clone()),
+            enqueued_at: pending.scheduled_at,
+        };
+        let PendingDelete {
+            notification: n,
+            ctx,
+            ..
+        } = pending;
+        self.status.started(UPDATER_WORKER, item);
+        let res = rt.block_on(ctx.scope(in_span("delete", async {
+            info!("processing scheduled delete");
+            self.confirmed_delete(&n, &ctx, None)
+                .await
+                .inspect_err(|e| {
+                    warn!("unable to delete profile for {}: {}", &n.id, e);
+                })
+        })));
+        self.status.finished(UPDATER_WORKER, &res);
+        match &res {
+            Err(e) if e.downcast_ref::<UnconfirmedDelete>().is_some() => {
+                let grace = Duration::seconds(self.settings.delete_grace_period as i64);
+                let pending = self.pending.schedule(n, ctx, Utc::now() + grace, ack);
+                info!(
+                    "retrying delete for {} at {}",
+                    &pending.notification.id, pending.due_at
+                );
+            }
+            _ => {
+                if let Some(ack) = ack {
+                    let _ = ack.send(res.is_ok());
+                }
+            }
+        }
+    }
+
+    /// Decides what an unknown operation stands for, `None` meaning it is ignored. Inferring
+    /// returns what CIS said so it is not asked again. Fails if CIS cannot be asked.
+    async fn resolve_unknown(
+        &self,
+        n: &Notification,
+    ) -> Result<Option<(Operation, Option<Lifecycle>)>, Error> {
+        Ok(match self.settings.unknown_operation {
+            UnknownOperationPolicy::Delete => {
+                metrics::UNKNOWN_DELETED.inc();
+                Some((Operation::Delete, None))
+            }
+            UnknownOperationPolicy::Ignore => {
+                metrics::UNKNOWN_IGNORED.inc();
+                None
+            }
+            UnknownOperationPolicy::Infer => match lifecycle(&self.person_api, &n.id).await? {
+                state @ Lifecycle::Active(_) => {
+                    metrics::UNKNOWN_INFERRED_UPDATE.inc();
+                    info!("inferred update for unknown operation on {}", &n.id);
+                    Some((Operation::Update, Some(state)))
+                }
+                state => {
+                    metrics::UNKNOWN_INFERRED_DELETE.inc();
+                    info!("inferred delete for unknown operation on {}", &n.id);
+                    Some((Operation::Delete, Some(state)))
+                }
+            },
+        })
+    }
+
+    /// Handles a single notification the way the updater loop does.
+    pub async fn process(&self, n: &Notification, ctx: &EventContext) -> Result<Value, Error> {
+        self.handle(n, ctx, &mut None).await
+    }
+
+    /// Scheduled deletes take the ack along, it is left in place otherwise.
+    async fn handle(
+        &self,
+        n: &Notification,
+        ctx: &EventContext,
+        ack: &mut Option<Ack>,
+    ) -> Result<Value, Error> {
+        let (operation, known) = match n.operation {
+            Operation::Unknown => match self.resolve_unknown(n).await.inspect_err(|e| {
+                warn!("unable to infer unknown operation for {}: {}", &n.id, e);
+            })? {
+                Some(resolved) => resolved,
+                None => {
+                    info!("ignoring unknown operation for {}", &n.id);
+                    return Ok(json!({ "ignored": true }));
+                }
+            },
+            ref operation => (operation.clone(), None),
+        };
+        match operation {
+            // CIS is asked again once the grace period has passed.
+            Operation::Delete if self.settings.delete_grace_period > 0 => {
+                let grace = Duration::seconds(self.settings.delete_grace_period as i64);
+                let pending =
+                    self.pending
+                        .schedule(n.clone(), ctx.clone(), Utc::now() + grace, ack.take());
+                info!("scheduled delete for {} at {}", &n.id, pending.due_at);
+                Ok(json!(pending))
+            }
+            Operation::Delete => {
+                in_span("delete", async {
+                    info!("processing");
+                    self.confirmed_delete(n, ctx, known).await.inspect_err(|e| {
+                        warn!("unable to delete profile for {}: {}", &n.id, e);
+                    })
+                })
+                .await
+            }
+            _ => {
+                if let Some(pending) = self.pending.cancel(&n.id) {
+                    info!("cancelled scheduled delete for {}", &n.id);
+                    let mut entry = AuditEntry::skipped(
+                        &n.id,
+                        Some(&pending.notification),
+                        Some(&pending.correlation_id),
+                        pending.scheduled_at,
+                    );
+                    entry.warning = Some(format!(
+                        "cancelled by {:?} notification ({})",
+                        n.operation, ctx.correlation_id
+                    ));
+                    self.audit.record(&entry);
+                }
+                in_span("update", async {
+                    info!("processing");
+                    let res = match known {
+                        Some(Lifecycle::Active(profile)) => {
+                            send_profile(&self.dino_park_settings, *profile).await
+                        }
+                        _ => update(&self.person_api, &self.dino_park_settings, n).await,
+                    };
+                    res.inspect_err(|e| {
+                        warn!("unable to update profile for {}: {}", &n.id, e);
+                    })
+                })
+                .await
+            }
+        }
+    }
+
+    /// Only deletes profiles CIS no longer knows as active, otherwise falls back to an update.
+    /// CIS is only asked if `known` does not already hold its answer.
+    async fn confirmed_delete(
+        &self,
+        n: &Notification,
+        ctx: &EventContext,
+        known: Option<Lifecycle>,
+    ) -> Result<Value, Error> {
+        let started_at = Utc::now();
+        let state = match known {
+            Some(state) => Ok(state),
+            None => lifecycle(&self.person_api, &n.id).await,
+        };
+        let state = match state {
+            Ok(state) => state,
+            Err(e) => {
+                let err = UnconfirmedDelete {
+                    user_id: n.id.clone(),
+                    cause: e.to_string(),
+                };
+                let mut entry =
+                    AuditEntry::skipped(&n.id, Some(n), Some(&ctx.correlation_id), started_at);
+                entry.error = Some(err.to_string());
+                self.audit.record(&entry);
+                return Err(err.into());
+            }
+        };
+        if let Lifecycle::Active(profile) = state {
+            let warning = format!(
+                "refusing to delete {}: still active in CIS, updating instead",
+                &n.id
+            );
+            warn!("{}", warning);
+            let uuid = profile.uuid.value.clone();
+            let res = send_profile(&self.dino_park_settings, *profile).await;
+            let mut entry =
+                AuditEntry::skipped(&n.id, Some(n), Some(&ctx.correlation_id), started_at);
+            entry.uuid = uuid;
+            entry.warning = Some(warning);
+            entry.error = res.as_ref().err().map(|e| e.to_string());
+            self.audit.record(&entry);
+            return res;
+        }
+        let res = delete(&self.dino_park_settings, &n.id).await;
+        self.audit.record(&AuditEntry::deletion(
+            &n.id,
+            Some(n),
+            Some(&ctx.correlation_id),
+            started_at,
+            &res,
+        ));
+        res.and_then(Deletion::into_result)
+    }
+}
+
+impl<T: CisClientTrait> Updater<InternalUpdaterClient> for InternalUpdater<T> {
     fn client(&self) -> InternalUpdaterClient {
         InternalUpdaterClient {
             sender: self.sender.clone(),
+            status: self.status.clone(),
         }
     }
 }
 
-pub async fn delete(dp: &DinoParkSettings, n: &Notification) -> Result<Value, Error> {
-    let id = n.id.clone();
+/// Outcome of removing a profile from all DinoPark services.
+#[derive(Serialize, Debug)]
+pub struct Deletion {
+    pub uuid: String,
+    pub outcomes: Outcomes,
+}
+
+impl Deletion {
+    pub fn is_ok(&self) -> bool {
+        self.outcomes.values().all(|o| o.ok)
+    }
+
+    pub fn into_result(self) -> Result<Value, Error> {
+        if self.is_ok() {
+            Ok(json!(self))
+        } else {
+            Err(UpdateError::Other.into())
+        }
+    }
+}
+
+pub async fn delete(dp: &DinoParkSettings, id: &str) -> Result<Deletion, Error> {
+    let uuid = uuid_by_user_id(dp, id).await?;
+    Ok(delete_uuid(dp, id, uuid).await)
+}
+
+/// Resolves the uuid through DinoPark, which still knows profiles CIS already removed.
+async fn uuid_by_user_id(dp: &DinoParkSettings, id: &str) -> Result<String, Error> {
     let uuid = Client::new()
         .get(format!("{}/{}", dp.uuid_by_user_id_endpoint, id))
-        .send()
+        .with_correlation_id()
+        .send_traced("uuid_lookup")
         .await?
         .json::<UuidByUserId>()
         .await?;
-    if let Some(uuid) = uuid.uuid {
-        let orgchart_delete = Client::new()
-            .post(format!("{}/{}", dp.orgchart_delete_endpoint, uuid))
-            .send()
-            .map_err(UpdateError::OrgchartDelete)
-            .map_ok(|_| info!("deleted from orgchart: {}", &id));
-        let search_delete = Client::new()
-            .post(format!("{}/{}", dp.search_delete_endpoint, uuid))
-            .send()
-            .map_err(UpdateError::SearchDelete)
-            .map_ok(|_| info!("deleted from search: {}", &id));
-        let picture_delete = Client::new()
-            .delete(format!("{}/{}", dp.picture_delete_endpoint, uuid))
-            .send()
-            .map_err(UpdateError::PicturesDelete)
-            .map_ok(|_| info!("deleted from pictures: {}", &id));
-        if let Some(ref groups_delete_endpoint) = dp.groups_delete_endpoint {
-            let groups_delete = Client::new()
-                .delete(format!("{groups_delete_endpoint}/{uuid}"))
-                .send()
-                .map_err(UpdateError::GroupsDelete)
-                .map_ok(|_| info!("updated groups for: {}", &id));
-            join4(
-                orgchart_delete,
-                search_delete,
-                picture_delete,
-                groups_delete,
-            )
-            .map(|r| match r {
-                (Ok(_), Ok(_), Ok(_), Ok(_)) => Ok(json!({})),
-                _ => Err(UpdateError::Other.into()),
+    uuid.uuid.ok_or_else(|| {
+        error!("cannot resolve uuid for: {}", id);
+        UpdateError::Other.into()
+    })
+}
+
+/// The identifiers needed to delete a profile.
+pub struct DeleteTarget {
+    pub user_id: Option<String>,
+    pub uuid: String,
+}
+
+/// Resolves `id` to the profile to delete. Lookups other than by user_id or uuid go through
+/// CIS, including inactive profiles.
+pub async fn delete_target(
+    person_api: &PersonApi,
+    dp: &DinoParkSettings,
+    id: &str,
+    lookup: Lookup,
+) -> Result<DeleteTarget, Error> {
+    match lookup {
+        Lookup::UserId => Ok(DeleteTarget {
+            user_id: Some(id.to_owned()),
+            uuid: uuid_by_user_id(dp, id).await?,
+        }),
+        Lookup::Uuid => Ok(DeleteTarget {
+            user_id: None,
+            uuid: id.to_owned(),
+        }),
+        _ => {
+            let profile = fetch_profile(person_api, id, &lookup.get_by()).await?;
+            let uuid = profile.uuid.value.ok_or_else(|| {
+                error!("cannot resolve uuid for: {}", id);
+                Error::from(UpdateError::Other)
+            })?;
+            Ok(DeleteTarget {
+                user_id: profile.user_id.value,
+                uuid,
             })
-            .await
-        } else {
-            join3(orgchart_delete, search_delete, picture_delete)
-                .map(|r| match r {
-                    (Ok(_), Ok(_), Ok(_)) => Ok(json!({})),
-                    _ => Err(UpdateError::Other.into()),
-                })
-                .await
         }
+    }
+}
+
+/// Removes the profile with `uuid` from all DinoPark services, `id` is only used for logging.
+pub async fn delete_uuid(dp: &DinoParkSettings, id: &str, uuid: String) -> Deletion {
+    let orgchart_delete = Client::new()
+        .post(format!("{}/{}", dp.orgchart_delete_endpoint, uuid))
+        .with_correlation_id()
+        .send_traced("orgchart_delete")
+        .inspect(|r| Sink::Orgchart.record(r))
+        .map_err(UpdateError::OrgchartDelete)
+        .inspect_ok(|_| info!("deleted from orgchart: {}", &id))
+        .map(|r| (Sink::Orgchart, Outcome::from(r)));
+    let search_delete = Client::new()
+        .post(format!("{}/{}", dp.search_delete_endpoint, uuid))
+        .with_correlation_id()
+        .send_traced("search_delete")
+        .inspect(|r| Sink::Search.record(r))
+        .map_err(UpdateError::SearchDelete)
+        .inspect_ok(|_| info!("deleted from search: {}", &id))
+        .map(|r| (Sink::Search, Outcome::from(r)));
+    let picture_delete = Client::new()
+        .delete(format!("{}/{}", dp.picture_delete_endpoint, uuid))
+        .with_correlation_id()
+        .send_traced("pictures_delete")
+        .inspect(|r| Sink::Pictures.record(r))
+        .map_err(UpdateError::PicturesDelete)
+        .inspect_ok(|_| info!("deleted from pictures: {}", &id))
+        .map(|r| (Sink::Pictures, Outcome::from(r)));
+    let outcomes = if let Some(ref groups_delete_endpoint) = dp.groups_delete_endpoint {
+        let groups_delete = Client::new()
+            .delete(format!("{groups_delete_endpoint}/{uuid}"))
+            .with_correlation_id()
+            .send_traced("groups_delete")
+            .inspect(|r| Sink::Groups.record(r))
+            .map_err(UpdateError::GroupsDelete)
+            .inspect_ok(|_| info!("deleted from groups: {}", &id))
+            .map(|r| (Sink::Groups, Outcome::from(r)));
+        let (o, s, p, g) = join4(
+            orgchart_delete,
+            search_delete,
+            picture_delete,
+            groups_delete,
+        )
+        .await;
+        vec![o, s, p, g]
     } else {
-        error!("cannot resolve uuid for: {}", &id);
-        Err(UpdateError::Other.into())
+        let (o, s, p) = join3(orgchart_delete, search_delete, picture_delete).await;
+        vec![o, s, p]
+    };
+    Deletion {
+        uuid,
+        outcomes: outcomes.into_iter().collect(),
     }
 }
 
-pub async fn update(
-    cis_client: &impl AsyncCisClientTrait,
-    dp: &DinoParkSettings,
-    n: &Notification,
-) -> Result<Value, Error> {
-    info!("getting profile for: {}", &n.id);
-    let profile = match cis_client.get_user_by(&n.id, &GetBy::UserId, None).await {
-        Ok(p) => p,
-        Err(_) => {
-            cis_client
-                .get_inactive_user_by(&n.id, &GetBy::UserId, None)
-                .await?
-        }
+/// What CIS currently knows about a profile.
+pub enum Lifecycle {
+    Active(Box<Profile>),
+    Inactive,
+    Gone,
+}
+
+fn is_empty(profile: &Profile) -> bool {
+    profile.user_id.value.is_none()
+}
+
+/// Turns unknown and empty profiles into `None`, keeping every other error.
+fn known(res: Result<Profile, Error>) -> Result<Option<Profile>, Error> {
+    match res {
+        Ok(p) if is_empty(&p) => Ok(None),
+        Ok(p) => Ok(Some(p)),
+        Err(e) if e.downcast_ref::<ProfileNotFound>().is_some() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Fails if CIS cannot be asked, so nothing is deleted just because CIS is down.
+pub async fn lifecycle(person_api: &PersonApi, id: &str) -> Result<Lifecycle, Error> {
+    let profile =
+        match known(in_span("get_user_by", person_api.get_user_by(id, &GetBy::UserId)).await)? {
+            Some(p) => Some(p),
+            None => known(
+                in_span(
+                    "get_inactive_user_by",
+                    person_api.get_inactive_user_by(id, &GetBy::UserId),
+                )
+                .await,
+            )?,
+        };
+    Ok(match profile {
+        Some(p) if p.active.value == Some(true) => Lifecycle::Active(Box::new(p)),
+        Some(_) => Lifecycle::Inactive,
+        None => Lifecycle::Gone,
+    })
+}
+
+/// Outcome of sending a profile to all DinoPark services.
+#[derive(Serialize, Debug)]
+pub struct ProfileUpdate {
+    pub user_id: String,
+    pub active: bool,
+    pub outcomes: Outcomes,
+}
+
+impl ProfileUpdate {
+    pub fn is_ok(&self) -> bool {
+        self.outcomes.values().all(|o| o.ok)
+    }
+}
+
+/// Fetches a profile from CIS, falling back to inactive profiles. CIS answers unknown ids with
+/// an empty profile, which is turned into `ProfileNotFound`.
+async fn fetch_profile(person_api: &PersonApi, id: &str, by: &GetBy) -> Result<Profile, Error> {
+    info!("getting profile for: {}", id);
+    let profile = match known(in_span("get_user_by", person_api.get_user_by(id, by)).await) {
+        Ok(Some(p)) => p,
+        _ => known(
+            in_span(
+                "get_inactive_user_by",
+                person_api.get_inactive_user_by(id, by),
+            )
+            .await,
+        )?
+        .ok_or_else(|| ProfileNotFound(id.to_owned()))?,
     };
     info!(
         "{} is active: {}",
         profile.user_id.value.as_deref().unwrap_or("?"),
         profile.active.value.as_ref().unwrap_or(&false)
     );
+    Ok(profile)
+}
+
+pub async fn update(
+    person_api: &PersonApi,
+    dp: &DinoParkSettings,
+    n: &Notification,
+) -> Result<Value, Error> {
+    let profile = fetch_profile(person_api, &n.id, &GetBy::UserId).await?;
     send_profile(dp, profile).await
 }
 
+/// Like `update` but reports the outcome for every sink.
+pub async fn resync(
+    person_api: &PersonApi,
+    dp: &DinoParkSettings,
+    id: &str,
+    lookup: Lookup,
+) -> Result<ProfileUpdate, Error> {
+    let profile = fetch_profile(person_api, id, &lookup.get_by()).await?;
+    fan_out_profile(dp, &profile).await
+}
+
+/// Only requests that could not be sent count as failures here, error responses from the
+/// services do not.
 pub async fn send_profile(dp: &DinoParkSettings, profile: Profile) -> Result<Value, Error> {
+    let update = fan_out_profile(dp, &profile).await?;
+    if update.outcomes.values().all(|o| o.error.is_none()) {
+        Ok(json!({}))
+    } else {
+        Err(UpdateError::Other.into())
+    }
+}
+
+async fn fan_out_profile(dp: &DinoParkSettings, profile: &Profile) -> Result<ProfileUpdate, Error> {
     let id = profile
         .user_id
         .value
         .clone()
         .unwrap_or_else(|| String::from("unknown"));
+    let value = payload::serialize(profile)?;
     let orgchart_update = Client::new()
         .post(&dp.orgchart_update_endpoint)
-        .json(&profile)
-        .send()
+        .with_correlation_id()
+        .sink_body(Body::json(
+            &payload::prepare(value.clone(), &dp.sinks.orgchart),
+            &dp.sinks.orgchart,
+        )?)
+        .send_traced("orgchart_update")
+        .inspect(|r| Sink::Orgchart.record(r))
         .map_err(UpdateError::OrgchartUpdate)
-        .map_ok(|_| info!("updated orgchart for: {}", &id));
+        .inspect_ok(|_| info!("updated orgchart for: {}", &id))
+        .map(|r| (Sink::Orgchart, Outcome::from(r)));
     let search_update = Client::new()
         .post(&dp.search_update_endpoint)
-        .json(&profile)
-        .send()
+        .with_correlation_id()
+        .sink_body(Body::json(
+            &payload::prepare(value.clone(), &dp.sinks.search),
+            &dp.sinks.search,
+        )?)
+        .send_traced("search_update")
+        .inspect(|r| Sink::Search.record(r))
         .map_err(UpdateError::SearchUpdate)
-        .map_ok(|_| info!("updated search for: {}", &id));
-    if let Some(ref groups_update_endpoint) = dp.groups_update_endpoint {
+        .inspect_ok(|_| info!("updated search for: {}", &id))
+        .map(|r| (Sink::Search, Outcome::from(r)));
+    let outcomes = if let Some(ref groups_update_endpoint) = dp.groups_update_endpoint {
         let groups_update = Client::new()
             .post(groups_update_endpoint)
-            .json(&profile)
-            .send()
+            .with_correlation_id()
+            .sink_body(Body::json(
+                &payload::prepare(value, &dp.sinks.groups),
+                &dp.sinks.groups,
+            )?)
+            .send_traced("groups_update")
+            .inspect(|r| Sink::Groups.record(r))
             .map_err(UpdateError::GroupsUpdate)
-            .map_ok(|_| info!("updated groups for: {}", &id));
-        join3(orgchart_update, search_update, groups_update)
-            .map(|r| match r {
-                (Ok(_), Ok(_), Ok(_)) => Ok(json!({})),
-                _ => Err(UpdateError::Other.into()),
-            })
-            .await
+            .inspect_ok(|_| info!("updated groups for: {}", &id))
+            .map(|r| (Sink::Groups, Outcome::from(r)));
+        let (o, s, g) = join3(orgchart_update, search_update, groups_update).await;
+        vec![o, s, g]
     } else {
-        join(orgchart_update, search_update)
-            .map(|r| match r {
-                (Ok(_), Ok(_)) => Ok(json!({})),
-                _ => Err(UpdateError::Other.into()),
-            })
-            .await
+        let (o, s) = join(orgchart_update, search_update).await;
+        vec![o, s]
+    };
+    Ok(ProfileUpdate {
+        user_id: id,
+        active: profile.active.value.unwrap_or(false),
+        outcomes: outcomes.into_iter().collect(),
+    })
+}
+
+/// Fetches profiles from CIS in pages of `page_size` and hands them over as soon as they are
+/// complete. Stops early once nobody waits for pages anymore.
+fn fetch_pages(
+    cis_client: &impl CisClientTrait,
+    filter: Option<&str>,
+    page_size: usize,
+    pages: SyncSender<Vec<Profile>>,
+) -> Result<(), Error> {
+    let mut page = vec![];
+    for res in cis_client.get_users_iter(filter)? {
+        let profiles = match res {
+            Ok(profiles) => profiles,
+            Err(e) => {
+                warn!("unable to fetch profiles: {}", e);
+                continue;
+            }
+        };
+        if page_size == 0 {
+            if pages.send(profiles).is_err() {
+                return Ok(());
+            }
+            continue;
+        }
+        page.extend(profiles);
+        while page.len() >= page_size {
+            let rest = page.split_off(page_size);
+            if pages.send(mem::replace(&mut page, rest)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+    if !page.is_empty() {
+        let _ = pages.send(page);
     }
+    Ok(())
 }
 
+/// Outcome of a bulk update. `pages` counts the requests sent to each sink, pages that could
+/// not be serialized or that a sink did not accept count as failed for that sink. `bytes` adds
+/// up the bodies sent to all sinks.
+#[derive(Serialize, Debug, Default)]
+pub struct BulkUpdate {
+    pub profiles: usize,
+    pub pages: usize,
+    pub failed_pages: BTreeMap<Sink, usize>,
+    pub bytes: usize,
+    pub peak_memory_kb: Option<u64>,
+}
+
+impl BulkUpdate {
+    pub fn is_ok(&self) -> bool {
+        self.failed_pages.is_empty()
+    }
+
+    pub fn into_result(self) -> Result<Value, Error> {
+        if self.is_ok() {
+            Ok(json!(self))
+        } else {
+            Err(format_err!(
+                "bulk update failed for {:?} pages",
+                self.failed_pages
+            ))
+        }
+    }
+
+    fn failed(&mut self, sink: Sink) {
+        *self.failed_pages.entry(sink).or_default() += 1;
+    }
+}
+
+async fn send_bulk(sink: Sink, endpoint: &str, body: Body, count: usize) -> (Sink, Outcome) {
+    let span = match sink {
+        Sink::Orgchart => "orgchart_bulk",
+        Sink::Search => "search_bulk",
+        _ => "groups_bulk",
+    };
+    let res = Client::new()
+        .post(endpoint)
+        .sink_body(body)
+        .send_traced(span)
+        .await;
+    sink.record(&res);
+    let outcome = Outcome::from(res);
+    if outcome.ok {
+        info!("updated {} for: {} profiles", sink, count);
+    } else {
+        error!("batch: {} failed: {:?}", sink, outcome);
+    }
+    (sink, outcome)
+}
+
+/// Pages are fetched from CIS on their own thread while earlier pages upload, with at most
+/// `pages_in_flight` pages waiting. Pages are cut into bodies of at most `max_body_bytes`.
+/// Every page is sent to every sink even if earlier pages failed. The peak memory is measured
+/// from the start of the update if the kernel allows resetting it.
 pub fn update_batch(
-    cis_client: &impl CisClientTrait,
+    rt: &Runtime,
+    cis_client: &(impl CisClientTrait + Sync),
     dp: &DinoParkSettings,
-) -> Result<Value, Error> {
+    settings: &BulkSettings,
+    filter: Option<&str>,
+) -> Result<BulkUpdate, Error> {
     debug!("getting bulk profiles");
-    let rt = Runtime::new()?;
-    let profiles_iter = cis_client.get_users_iter(None)?;
-    for profiles in profiles_iter.flatten() {
-        info!("{}", profiles.len());
-        rt.block_on(
-            async move {
-                let mp = multipart::Part::text(serde_json::to_string(&profiles)?)
-                    .file_name("data")
-                    .mime_str("application/json")?;
-                let form = multipart::Form::new().part("data", mp);
-                let orgchart_update = Client::new()
-                    .post(&dp.orgchart_bulk_endpoint)
-                    .multipart(form)
-                    .send()
-                    .map_err(UpdateError::OrgchartUpdate)
-                    .map_err(|e| {
-                        error!("batch: {}", e);
-                        e
-                    })
-                    .map_ok(|_| info!("updated orgchart for: {} profiles", profiles.len()));
-                let mp = multipart::Part::text(serde_json::to_string(&profiles)?)
-                    .file_name("data")
-                    .mime_str("application/json")?;
-                let form = multipart::Form::new().part("data", mp);
-                let search_update = Client::new()
-                    .post(&dp.search_bulk_endpoint)
-                    .multipart(form)
-                    .send()
-                    .map_err(UpdateError::SearchUpdate)
-                    .map_err(|e| {
-                        error!("batch: {}", e);
-                        e
-                    })
-                    .map_ok(|_| info!("updated search for: {} profiles", profiles.len()));
-                if let Some(ref groups_bulk_endpoint) = dp.groups_bulk_endpoint {
-                    let mp = multipart::Part::text(serde_json::to_string(&profiles)?)
-                        .file_name("data")
-                        .mime_str("application/json")?;
-                    let form = multipart::Form::new().part("data", mp);
-                    let groups_update = Client::new()
-                        .post(groups_bulk_endpoint)
-                        .multipart(form)
-                        .send()
-                        .map_err(UpdateError::GroupsUpdate)
-                        .map_err(|e| {
-                            error!("batch: {}", e);
-                            e
-                        })
-                        .map_ok(|_| info!("updated groups for: {} profiles", profiles.len()));
-                    join3(orgchart_update, search_update, groups_update)
-                        .map(|_| Ok::<(), Error>(()))
-                        .await
-                } else {
-                    join(orgchart_update, search_update)
-                        .map(|_| Ok::<(), Error>(()))
-                        .await
+    let measured = metrics::reset_peak_memory();
+    let mut update = BulkUpdate::default();
+    let mut sinks = vec![
+        (
+            Sink::Orgchart,
+            &dp.orgchart_bulk_endpoint,
+            &dp.sinks.orgchart,
+        ),
+        (Sink::Search, &dp.search_bulk_endpoint, &dp.sinks.search),
+    ];
+    if let Some(ref groups_bulk_endpoint) = dp.groups_bulk_endpoint {
+        sinks.push((Sink::Groups, groups_bulk_endpoint, &dp.sinks.groups));
+    }
+    let sink_settings = sinks.iter().map(|&(_, _, s)| s).collect::<Vec<_>>();
+    let (tx, rx) = sync_channel(settings.pages_in_flight.max(1));
+    thread::scope(|scope| {
+        let fetcher = scope.spawn(move || fetch_pages(cis_client, filter, settings.page_size, tx));
+        for profiles in rx {
+            info!("{}", profiles.len());
+            let mut rest = &profiles[..];
+            while !rest.is_empty() {
+                update.pages += 1;
+                let batch = match body::bulk(rest, &sink_settings, settings.max_body_bytes) {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        error!("unable to serialize batch: {}", e);
+                        sinks.iter().for_each(|&(sink, _, _)| update.failed(sink));
+                        break;
+                    }
+                };
+                rest = &rest[batch.consumed..];
+                let bytes = batch.bodies.iter().map(Body::size).sum::<usize>();
+                update.profiles += batch.count;
+                update.bytes += bytes;
+                debug!("serialized {} profiles into {} bytes", batch.count, bytes);
+                let count = batch.count;
+                let outcomes =
+                    rt.block_on(join_all(sinks.iter().zip(batch.bodies).map(
+                        |(&(sink, endpoint, _), body)| send_bulk(sink, endpoint, body, count),
+                    )));
+                for (sink, outcome) in outcomes {
+                    if !outcome.ok {
+                        update.failed(sink);
+                    }
                 }
             }
-            .map(|r| {
-                if let Err(e) = r {
-                    error!("unable to process batch: {}", e)
-                };
-            }),
-        )
+        }
+        fetcher
+            .join()
+            .map_err(|_| format_err!("fetching profiles panicked"))?
+    })?;
+    update.peak_memory_kb = metrics::peak_memory_kb().filter(|_| measured);
+    info!(
+        "done bulk updating {} profiles in {} pages ({} bytes), failed pages: {:?}, peak memory: {:?} kB",
+        update.profiles, update.pages, update.bytes, update.failed_pages, update.peak_memory_kb
+    );
+    Ok(update)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audit::AuditQuery;
+    use crate::settings::AuditSettings;
+    use crate::settings::SinksSettings;
+    use actix_web::web;
+    use actix_web::App;
+    use actix_web::HttpRequest;
+    use actix_web::HttpResponse;
+    use actix_web::HttpServer;
+    use cis_client::settings::ClientConfig;
+    use failure::format_err;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    const USER_ID: &str = "ad|Mozilla-LDAP|hknall";
+    const UUID: &str = "some-uuid";
+    /// Nothing listens here, so CIS cannot be asked.
+    const UNREACHABLE: &str = "http://127.0.0.1:1";
+
+    /// What the CIS stand-in knows about `USER_ID`.
+    #[derive(Clone, Copy)]
+    enum Known {
+        Active,
+        Inactive,
+        Gone,
+    }
+
+    type Requests = Arc<Mutex<Vec<String>>>;
+
+    /// Serves CIS and DinoPark for `USER_ID` and records what DinoPark receives.
+    struct StandIn {
+        base: String,
+        requests: Requests,
+    }
+
+    async fn cis(req: HttpRequest, known: web::Data<Known>) -> HttpResponse {
+        let inactive = req.query_string().contains("active=false");
+        let mut profile = Profile::default();
+        match (**known, inactive) {
+            (Known::Active, false) => profile.active.value = Some(true),
+            (Known::Inactive, true) => profile.active.value = Some(false),
+            // CIS answers unknown users with an empty profile.
+            _ => return HttpResponse::Ok().json(profile),
+        }
+        profile.user_id.value = Some(USER_ID.to_owned());
+        profile.uuid.value = Some(UUID.to_owned());
+        HttpResponse::Ok().json(profile)
+    }
+
+    async fn dino_park(req: HttpRequest, requests: web::Data<Requests>) -> HttpResponse {
+        if req.path().starts_with("/uuid/") {
+            return HttpResponse::Ok().json(json!({ "uuid": UUID }));
+        }
+        requests
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", req.method(), req.path()));
+        HttpResponse::Ok().json(json!({}))
+    }
+
+    impl StandIn {
+        fn start(known: Known) -> Self {
+            let requests = Requests::default();
+            let recorded = requests.clone();
+            let (tx, rx) = channel();
+            spawn(move || {
+                actix_rt::System::new().block_on(async move {
+                    let server = HttpServer::new(move || {
+                        App::new()
+                            .app_data(web::Data::new(known))
+                            .app_data(web::Data::new(recorded.clone()))
+                            .route(
+                                "/token",
+                                web::post().to(|| async {
+                                    HttpResponse::Ok()
+                                        .json(json!({ "access_token": "t", "expires_in": 3600 }))
+                                }),
+                            )
+                            .route("/cis/{tail:.*}", web::get().to(cis))
+                            .default_service(web::to(dino_park))
+                    })
+                    .workers(1)
+                    .bind(("127.0.0.1", 0))
+                    .unwrap();
+                    tx.send(server.addrs()[0]).unwrap();
+                    server.run().await
+                })
+            });
+            StandIn {
+                base: format!("http://{}", rx.recv().unwrap()),
+                requests,
+            }
+        }
+
+        fn person_api(base: &str) -> PersonApi {
+            let client_config = ClientConfig {
+                client_id: String::from("lookout"),
+                client_secret: String::from("secret"),
+                audience: String::from("api.sso.mozilla.com"),
+                token_endpoint: format!("{}/token", base),
+                scopes: String::new(),
+            };
+            PersonApi::with_endpoint(client_config, format!("{}/cis/", base))
+        }
+
+        fn dino_park(&self) -> DinoParkSettings {
+            let base = &self.base;
+            DinoParkSettings {
+                search_update_endpoint: format!("{}/search/update", base),
+                orgchart_update_endpoint: format!("{}/orgchart/update", base),
+                groups_update_endpoint: None,
+                search_bulk_endpoint: format!("{}/search/bulk", base),
+                orgchart_bulk_endpoint: format!("{}/orgchart/bulk", base),
+                groups_bulk_endpoint: None,
+                search_delete_endpoint: format!("{}/search/delete", base),
+                orgchart_delete_endpoint: format!("{}/orgchart/delete", base),
+                picture_delete_endpoint: format!("{}/pictures", base),
+                groups_delete_endpoint: None,
+                uuid_by_user_id_endpoint: format!("{}/uuid", base),
+                sinks: SinksSettings::default(),
+            }
+        }
+
+        /// With `cis_reachable` false only DinoPark is served.
+        fn updater(
+            &self,
+            cis_reachable: bool,
+            settings: UpdaterSettings,
+            audit: &AuditSettings,
+        ) -> InternalUpdater<()> {
+            let cis = if cis_reachable {
+                &self.base
+            } else {
+                UNREACHABLE
+            };
+            InternalUpdater::new(
+                (),
+                StandIn::person_api(cis),
+                self.dino_park(),
+                settings,
+                AuditLog::new(audit),
+                PendingDeletes::load(audit).unwrap(),
+            )
+        }
+
+        fn requests(&self) -> Vec<String> {
+            let mut requests = self.requests.lock().unwrap().clone();
+            requests.sort();
+            requests
+        }
+    }
+
+    fn audit_settings() -> AuditSettings {
+        let dir = std::env::temp_dir();
+        let id = uuid::Uuid::new_v4();
+        AuditSettings {
+            path: dir
+                .join(format!("audit-{}.jsonl", id))
+                .to_string_lossy()
+                .into_owned(),
+            pending_path: dir
+                .join(format!("pending-{}.json", id))
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+
+    fn notification(operation: Operation) -> Notification {
+        Notification {
+            operation,
+            id: String::from(USER_ID),
+            time: 1551963674.0,
+        }
+    }
+
+    fn with_grace_period() -> UpdaterSettings {
+        UpdaterSettings {
+            delete_grace_period: 3600,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_only_unknown_profiles_count_as_gone() {
+        assert!(known(Ok(Profile::default())).unwrap().is_none());
+        assert!(known(Err(ProfileNotFound(String::from("hknall")).into()))
+            .unwrap()
+            .is_none());
+        assert!(known(Err(format_err!("connection refused"))).is_err());
+    }
+
+    // The unknown operation counters are only touched here, so every case can check them.
+    #[test]
+    fn test_resolve_unknown_by_policy() {
+        use UnknownOperationPolicy::*;
+        let counters = [
+            &metrics::UNKNOWN_DELETED,
+            &metrics::UNKNOWN_IGNORED,
+            &metrics::UNKNOWN_INFERRED_UPDATE,
+            &metrics::UNKNOWN_INFERRED_DELETE,
+        ];
+        let cases = [
+            (Delete, Known::Active, Some((Operation::Delete, None)), 0),
+            (Ignore, Known::Active, None, 1),
+            (
+                Infer,
+                Known::Active,
+                Some((Operation::Update, Some("active"))),
+                2,
+            ),
+            (
+                Infer,
+                Known::Inactive,
+                Some((Operation::Delete, Some("inactive"))),
+                3,
+            ),
+            (
+                Infer,
+                Known::Gone,
+                Some((Operation::Delete, Some("gone"))),
+                3,
+            ),
+        ];
+        let audit = audit_settings();
+        let rt = Runtime::new().unwrap();
+        for (policy, known, decision, counter) in cases {
+            let settings = UpdaterSettings {
+                unknown_operation: policy,
+                ..Default::default()
+            };
+            let updater = StandIn::start(known).updater(true, settings, &audit);
+            let before: Vec<u64> = counters.iter().map(|c| c.get()).collect();
+            let resolved = rt
+                .block_on(updater.resolve_unknown(&notification(Operation::Unknown)))
+                .unwrap()
+                .map(|(operation, state)| {
+                    let state = state.map(|state| match state {
+                        Lifecycle::Active(_) => "active",
+                        Lifecycle::Inactive => "inactive",
+                        Lifecycle::Gone => "gone",
+                    });
+                    (operation, state)
+                });
+            assert_eq!(resolved, decision, "{:?}", policy);
+            for (i, c) in counters.iter().enumerate() {
+                let inc = if i == counter { 1 } else { 0 };
+                assert_eq!(c.get(), before[i] + inc, "{:?} counter {}", policy, i);
+            }
+        }
+
+        let settings = UpdaterSettings {
+            unknown_operation: Infer,
+            ..Default::default()
+        };
+        let updater = StandIn::start(Known::Active).updater(false, settings, &audit);
+        let before: Vec<u64> = counters.iter().map(|c| c.get()).collect();
+        assert!(rt
+            .block_on(updater.resolve_unknown(&notification(Operation::Unknown)))
+            .is_err());
+        let after: Vec<u64> = counters.iter().map(|c| c.get()).collect();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn test_update_cancels_scheduled_delete() {
+        let stand_in = StandIn::start(Known::Active);
+        let audit = audit_settings();
+        let updater = stand_in.updater(true, with_grace_period(), &audit);
+        let rt = Runtime::new().unwrap();
+        let ctx = EventContext::default();
+
+        rt.block_on(updater.process(&notification(Operation::Delete), &ctx))
+            .unwrap();
+        assert_eq!(updater.pending_deletes().list().len(), 1);
+        assert!(stand_in.requests().is_empty());
+
+        rt.block_on(updater.process(&notification(Operation::Update), &EventContext::default()))
+            .unwrap();
+        assert!(updater.pending_deletes().list().is_empty());
+        assert!(PendingDeletes::load(&audit).unwrap().list().is_empty());
+        assert_eq!(
+            stand_in.requests(),
+            vec!["POST /orgchart/update", "POST /search/update"]
+        );
+        let entries = AuditLog::new(&audit).query(&AuditQuery::default()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].correlation_id, Some(ctx.correlation_id));
+        assert!(entries[0]
+            .warning
+            .as_deref()
+            .unwrap()
+            .starts_with("cancelled by Update"));
+        std::fs::remove_file(&audit.path).unwrap();
+        std::fs::remove_file(&audit.pending_path).unwrap();
+    }
+
+    #[test]
+    fn test_due_delete_is_executed() {
+        let stand_in = StandIn::start(Known::Gone);
+        let audit = audit_settings();
+        let updater = stand_in.updater(true, with_grace_period(), &audit);
+        let rt = Runtime::new().unwrap();
+        let (ack, mut acked) = oneshot::channel();
+        updater.pending.schedule(
+            notification(Operation::Delete),
+            EventContext::default(),
+            Utc::now(),
+            Some(ack),
+        );
+
+        for (pending, ack) in updater.pending.take_due() {
+            updater.run_scheduled_delete(&rt, pending, ack);
+        }
+        assert_eq!(acked.try_recv(), Ok(Some(true)));
+        assert!(updater.pending_deletes().list().is_empty());
+        assert_eq!(
+            stand_in.requests(),
+            vec![
+                "DELETE /pictures/some-uuid",
+                "POST /orgchart/delete/some-uuid",
+                "POST /search/delete/some-uuid",
+            ]
+        );
+        let entries = AuditLog::new(&audit).query(&AuditQuery::default()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].uuid.as_deref(), Some(UUID));
+        assert!(entries[0].error.is_none());
+        std::fs::remove_file(&audit.path).unwrap();
+        std::fs::remove_file(&audit.pending_path).unwrap();
+    }
+
+    #[test]
+    fn test_unconfirmed_due_delete_is_scheduled_again() {
+        let stand_in = StandIn::start(Known::Gone);
+        let audit = audit_settings();
+        let updater = stand_in.updater(false, with_grace_period(), &audit);
+        let rt = Runtime::new().unwrap();
+        let (ack, mut acked) = oneshot::channel();
+        updater.pending.schedule(
+            notification(Operation::Delete),
+            EventContext::default(),
+            Utc::now(),
+            Some(ack),
+        );
+
+        for (pending, ack) in updater.pending.take_due() {
+            updater.run_scheduled_delete(&rt, pending, ack);
+        }
+        assert_eq!(acked.try_recv(), Ok(None));
+        let pending = PendingDeletes::load(&audit).unwrap().list();
+        assert_eq!(pending.len(), 1);
+        assert!(pending[0].due_at > Utc::now());
+        assert!(stand_in.requests().is_empty());
+        let entries = AuditLog::new(&audit).query(&AuditQuery::default()).unwrap();
+        assert!(entries[0].error.is_some());
+        std::fs::remove_file(&audit.path).unwrap();
+        std::fs::remove_file(&audit.pending_path).unwrap();
     }
-    info!("done bulk updating");
-    Ok(json!({}))
 }